@@ -1,10 +1,13 @@
+use routefinder::config::Profile;
 use routefinder::error::Error;
 use routefinder::fresh_file_finder::{AppState, build_ui, ui::{BUTTON_PRESSED, CALCULATE_PRESSED, CLEAR_PRESSED, OFF_ROUTE_PRESSED, OFF_ROUTE_UP_PRESSED, OFF_ROUTE_DOWN_PRESSED, OFF_ROUTE_REROUTE_PRESSED, EXIT_OFF_ROUTE_PRESSED}, app::ButtonPress};
+use routefinder::gui::{self, forward_stderr, wait_for_success, CALCULATION_COMPLETE, CALCULATION_ERROR, OUTPUT_UPDATE};
 use druid::{AppLauncher, WindowDesc, EventCtx, Event, Env, WidgetExt, ExtEventSink, Target, Selector};
 use druid::widget::Controller;
 use std::fs::File;
 use std::io::{Write, BufRead, BufReader};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
 
 type Result<T, E = Error> = core::result::Result<T, E>;
 
@@ -21,9 +24,6 @@ fn expand_tilde(path: &str) -> String {
 }
 
 // Custom events for background thread communication
-pub const OUTPUT_UPDATE: Selector<String> = Selector::new("output-update");
-pub const CALCULATION_COMPLETE: Selector<()> = Selector::new("calculation-complete");
-pub const CALCULATION_ERROR: Selector<String> = Selector::new("calculation-error");
 pub const SEED_FOUND: Selector<i32> = Selector::new("seed-found");
 
 fn main() -> Result<()> {
@@ -122,9 +122,10 @@ fn execute_calculate(data: &mut AppState, event_sink: ExtEventSink) -> Result<()
     let save_file_path = data.save_file_path.clone();
     let scripts_dir_path = data.scripts_dir_path.clone();
     let offset = data.button_history.last().map(|bp| bp.offset + 1).unwrap_or(data.offset as u32) as i32;
+    let profile = data.profile.clone();
     
     std::thread::spawn(move || {
-        execute_calculate_background(button_history, script_file, save_file_path, scripts_dir_path, offset, event_sink);
+        execute_calculate_background(button_history, script_file, save_file_path, scripts_dir_path, offset, profile, event_sink);
     });
     
     Ok(())
@@ -137,6 +138,7 @@ fn execute_calculate_background(
     save_file_path: String,
     scripts_dir_path: String,
     offset: i32,
+    profile: Arc<Profile>,
     event_sink: ExtEventSink,
 ) {
     // Generate reverse-rng input file
@@ -173,8 +175,10 @@ fn execute_calculate_background(
     event_sink.submit_command(OUTPUT_UPDATE, "\n=== Running Reverse RNG ===\n".to_string(), Target::Auto).ok();
     
     let mut reverse_rng_child = match Command::new("cargo")
-        .args(&["+nightly", "run", "--release", "--features", "simd_nightly", "--bin", "routefinder", "--", "reverse-rng", temp_file_path])
+        .args(&["+nightly", "run", "--quiet", "--release", "--features", "simd_nightly", "--bin", "routefinder", "--", "reverse-rng", temp_file_path])
+        .args(gui::reverse_rng_args(&profile))
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn() {
         Ok(child) => child,
         Err(e) => {
@@ -183,6 +187,8 @@ fn execute_calculate_background(
         }
     };
     
+    let stderr_thread = forward_stderr(&mut reverse_rng_child, &event_sink);
+    
     let stdout = reverse_rng_child.stdout.take().unwrap();
     let reader = BufReader::new(stdout);
     let mut reverse_rng_output = String::new();
@@ -201,8 +207,7 @@ fn execute_calculate_background(
         }
     }
     
-    if let Err(e) = reverse_rng_child.wait() {
-        event_sink.submit_command(CALCULATION_ERROR, e.to_string(), Target::Auto).ok();
+    if !wait_for_success(reverse_rng_child, stderr_thread, &event_sink) {
         return;
     }
     
@@ -222,12 +227,14 @@ fn execute_calculate_background(
     
     let expanded_scripts_dir = expand_tilde(&scripts_dir_path);
     let mut route_child = match Command::new("cargo")
-        .args(&["run", "--release", "--bin", "routefinder", "--", "run", &script_file, 
+        .args(&["run", "--quiet", "--release", "--bin", "routefinder", "--", "run", &script_file, 
                "--save-file", &save_file_path,
                "--scripts-dir", &expanded_scripts_dir,
                "--lua-var", &format!("AthenaSeed={}", seed),
                "--lua-var", &format!("AthenaOffset={}", offset - 1)])
+        .args(gui::run_args(&profile))
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn() {
         Ok(child) => child,
         Err(e) => {
@@ -236,6 +243,8 @@ fn execute_calculate_background(
         }
     };
     
+    let stderr_thread = forward_stderr(&mut route_child, &event_sink);
+    
     let stdout = route_child.stdout.take().unwrap();
     let reader = BufReader::new(stdout);
     
@@ -251,8 +260,7 @@ fn execute_calculate_background(
         }
     }
     
-    if let Err(e) = route_child.wait() {
-        event_sink.submit_command(CALCULATION_ERROR, e.to_string(), Target::Auto).ok();
+    if !wait_for_success(route_child, stderr_thread, &event_sink) {
         return;
     }
     
@@ -285,11 +293,12 @@ fn execute_off_route_preview(data: &mut AppState, event_sink: ExtEventSink) -> R
     let save_file_path = data.save_file_path.clone(); 
     let scripts_dir_path = data.scripts_dir_path.clone();
     let offset_off_by = data.offset_off_by;
+    let profile = data.profile.clone();
     
     std::thread::spawn(move || {
         execute_off_route_preview_background(
             script_file, save_file_path, scripts_dir_path, 
-            chamber, offset_off_by, event_sink
+            chamber, offset_off_by, profile, event_sink
         );
     });
     
@@ -305,11 +314,12 @@ fn execute_off_route_reroute(data: &mut AppState, event_sink: ExtEventSink) -> R
     let save_file_path = data.save_file_path.clone();
     let scripts_dir_path = data.scripts_dir_path.clone(); 
     let actual_offset = data.offset_off_by;
+    let profile = data.profile.clone();
     
     std::thread::spawn(move || {
         execute_off_route_reroute_background(
             script_file, save_file_path, scripts_dir_path,
-            chamber, actual_offset, event_sink
+            chamber, actual_offset, profile, event_sink
         );
     });
     
@@ -322,18 +332,21 @@ fn execute_off_route_preview_background(
     scripts_dir_path: String,
     chamber: i32,
     offset_off_by: i32,
+    profile: Arc<Profile>,
     event_sink: ExtEventSink,
 ) {
     event_sink.submit_command(OUTPUT_UPDATE, "\n=== Is this yours? ===\n".to_string(), Target::Auto).ok();
     
     let expanded_scripts_dir = expand_tilde(&scripts_dir_path);
     let mut route_child = match Command::new("cargo")
-        .args(&["run", "--release", "--bin", "routefinder", "--", "run", &script_file,
+        .args(&["run", "--quiet", "--release", "--bin", "routefinder", "--", "run", &script_file,
                "--save-file", &save_file_path,
                "--scripts-dir", &expanded_scripts_dir,
                "--lua-var", &format!("FirstChamberOffRoute={}", chamber),
                "--lua-var", &format!("OffsetOffBy={}", offset_off_by)])
+        .args(gui::run_args(&profile))
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn() {
         Ok(child) => child,
         Err(e) => {
@@ -342,6 +355,8 @@ fn execute_off_route_preview_background(
         }
     };
     
+    let stderr_thread = forward_stderr(&mut route_child, &event_sink);
+    
     let stdout = route_child.stdout.take().unwrap();
     let reader = BufReader::new(stdout);
     
@@ -357,8 +372,7 @@ fn execute_off_route_preview_background(
         }
     }
     
-    if let Err(e) = route_child.wait() {
-        event_sink.submit_command(CALCULATION_ERROR, e.to_string(), Target::Auto).ok();
+    if !wait_for_success(route_child, stderr_thread, &event_sink) {
         return;
     }
     
@@ -371,18 +385,21 @@ fn execute_off_route_reroute_background(
     scripts_dir_path: String, 
     chamber: i32,
     actual_offset: i32,
+    profile: Arc<Profile>,
     event_sink: ExtEventSink,
 ) {
     event_sink.submit_command(OUTPUT_UPDATE, "\n=== Off Route Reroute ===\n".to_string(), Target::Auto).ok();
     
     let expanded_scripts_dir = expand_tilde(&scripts_dir_path);
     let mut route_child = match Command::new("cargo")
-        .args(&["run", "--release", "--bin", "routefinder", "--", "run", &script_file,
+        .args(&["run", "--quiet", "--release", "--bin", "routefinder", "--", "run", &script_file,
                "--save-file", &save_file_path, 
                "--scripts-dir", &expanded_scripts_dir,
                "--lua-var", &format!("FirstChamberOffRoute={}", chamber),
                "--lua-var", &format!("ActualOffset={}", actual_offset)])
+        .args(gui::run_args(&profile))
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn() {
         Ok(child) => child,
         Err(e) => {
@@ -391,6 +408,8 @@ fn execute_off_route_reroute_background(
         }
     };
     
+    let stderr_thread = forward_stderr(&mut route_child, &event_sink);
+    
     let stdout = route_child.stdout.take().unwrap();
     let reader = BufReader::new(stdout);
     
@@ -406,10 +425,9 @@ fn execute_off_route_reroute_background(
         }
     }
     
-    if let Err(e) = route_child.wait() {
-        event_sink.submit_command(CALCULATION_ERROR, e.to_string(), Target::Auto).ok();
+    if !wait_for_success(route_child, stderr_thread, &event_sink) {
         return;
     }
     
     event_sink.submit_command(CALCULATION_COMPLETE, (), Target::Auto).ok();
-}
\ No newline at end of file
+}
@@ -1,18 +1,16 @@
+use routefinder::config::Profile;
 use routefinder::error::Error;
+use routefinder::gui::{self, forward_stderr, wait_for_success, CALCULATION_COMPLETE, CALCULATION_ERROR, OUTPUT_UPDATE};
 use routefinder::sack_finder::{AppState, build_ui, ui::{CALCULATE_PRESSED, CLEAR_PRESSED}};
 use druid::{AppLauncher, WindowDesc, EventCtx, Event, Env, WidgetExt, ExtEventSink, Target};
 use druid::widget::Controller;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Write, BufRead, BufReader};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
 
 type Result<T, E = Error> = core::result::Result<T, E>;
 
-// Custom events for background thread communication
-pub const OUTPUT_UPDATE: druid::Selector<String> = druid::Selector::new("output-update");
-pub const CALCULATION_COMPLETE: druid::Selector<()> = druid::Selector::new("calculation-complete");
-pub const CALCULATION_ERROR: druid::Selector<String> = druid::Selector::new("calculation-error");
-
 fn main() -> Result<()> {
     struct AppController;
     
@@ -72,15 +70,16 @@ fn execute_calculate(data: &mut AppState, event_sink: ExtEventSink) -> Result<()
     
     // Clone data needed for background thread
     let range_data = data.generate_range_format();
+    let profile = data.profile.clone();
     
     std::thread::spawn(move || {
-        execute_calculate_background(range_data, event_sink);
+        execute_calculate_background(range_data, profile, event_sink);
     });
     
     Ok(())
 }
 
-fn execute_calculate_background(range_data: String, event_sink: ExtEventSink) {
+fn execute_calculate_background(range_data: String, profile: Arc<Profile>, event_sink: ExtEventSink) {
     // Generate range format file
     let temp_file_path = "/tmp/sack_finder_range_input.txt";
     let mut file = match File::create(temp_file_path) {
@@ -108,8 +107,10 @@ fn execute_calculate_background(range_data: String, event_sink: ExtEventSink) {
     event_sink.submit_command(OUTPUT_UPDATE, "\n=== Running Reverse RNG ===\n".to_string(), Target::Auto).ok();
     
     let mut reverse_rng_child = match Command::new("cargo")
-        .args(&["+nightly", "run", "--release", "--features", "simd_nightly", "--bin", "routefinder", "--", "reverse-rng", temp_file_path])
+        .args(&["+nightly", "run", "--quiet", "--release", "--features", "simd_nightly", "--bin", "routefinder", "--", "reverse-rng", temp_file_path])
+        .args(gui::reverse_rng_args(&profile))
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn() {
         Ok(child) => child,
         Err(e) => {
@@ -118,10 +119,11 @@ fn execute_calculate_background(range_data: String, event_sink: ExtEventSink) {
         }
     };
     
+    let stderr_thread = forward_stderr(&mut reverse_rng_child, &event_sink);
+    
     let stdout = reverse_rng_child.stdout.take().unwrap();
-    let reader = std::io::BufReader::new(stdout);
+    let reader = BufReader::new(stdout);
     
-    use std::io::BufRead;
     for line in reader.lines() {
         match line {
             Ok(line_str) => {
@@ -134,12 +136,11 @@ fn execute_calculate_background(range_data: String, event_sink: ExtEventSink) {
         }
     }
     
-    if let Err(e) = reverse_rng_child.wait() {
-        event_sink.submit_command(CALCULATION_ERROR, e.to_string(), Target::Auto).ok();
+    if !wait_for_success(reverse_rng_child, stderr_thread, &event_sink) {
         return;
     }
     
     // Clean up temp file and signal completion
     std::fs::remove_file(temp_file_path).ok();
     event_sink.submit_command(CALCULATION_COMPLETE, (), Target::Auto).ok();
-}
\ No newline at end of file
+}
@@ -0,0 +1,163 @@
+use crate::error::Error;
+use mlua::{ChunkMode, Function, Lua};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DEFAULT_CACHE_DIR: &str = ".routefinder-cache";
+
+/// On-disk cache of compiled Lua chunks and decoded saves. Entries are
+/// keyed by a hash of their contents, so an edited file simply misses and
+/// replaces its stale entry.
+///
+/// The directory must be trusted: its bytecode is loaded as it is, and
+/// Lua doesn't verify binary chunks. The [`Sandbox`](crate::sandbox::Sandbox)
+/// denies scripts any access to it.
+#[derive(Clone, Debug)]
+pub struct StartupCache {
+    dir: PathBuf,
+}
+
+impl StartupCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        StartupCache { dir: dir.into() }
+    }
+
+    /// Compile `source`, read from `path`, reusing the bytecode cached by an
+    /// earlier run if the source hasn't changed since.
+    pub fn load_chunk<'lua>(
+        &self,
+        lua: &'lua Lua,
+        path: &Path,
+        source: &[u8],
+        name: Option<&str>,
+    ) -> mlua::Result<Function<'lua>> {
+        let prefix = path_prefix("chunk", path);
+        let entry = format!("{}-{:016x}.luac", prefix, content_hash(source, name));
+        if let Ok(bytecode) = std::fs::read(self.dir.join(&entry)) {
+            if let Ok(function) = lua.load(&bytecode).set_mode(ChunkMode::Binary).into_function() {
+                return Ok(function);
+            }
+        }
+
+        let mut chunk = lua.load(source);
+        if let Some(name) = name {
+            chunk = chunk.set_name(name);
+        }
+        let function = chunk.into_function()?;
+        self.store(&prefix, &entry, &function.dump(false));
+        Ok(function)
+    }
+
+    /// The Lua state of the save file `save`, read from `path`, as luabins
+    /// ready to load into the VM: already parsed out of the save and
+    /// decompressed. `decode` computes it if it isn't cached.
+    pub fn save_state<F>(&self, path: &Path, save: &[u8], decode: F) -> Result<Vec<u8>, Error>
+    where
+        F: FnOnce() -> Result<Vec<u8>, Error>,
+    {
+        let prefix = path_prefix("save", path);
+        let entry = format!("{}-{:016x}.luabins", prefix, content_hash(save, None));
+        if let Ok(state) = std::fs::read(self.dir.join(&entry)) {
+            return Ok(state);
+        }
+        let state = decode()?;
+        self.store(&prefix, &entry, &state);
+        Ok(state)
+    }
+
+    /// Write `entry`, replacing any other entry sharing its `prefix`. A cache
+    /// that can't be written only costs time, so failures are just reported.
+    fn store(&self, prefix: &str, entry: &str, data: &[u8]) {
+        static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let result = (|| -> std::io::Result<()> {
+            std::fs::create_dir_all(&self.dir)?;
+            for stale in std::fs::read_dir(&self.dir)? {
+                let stale = stale?.file_name();
+                let stale = stale.to_string_lossy();
+                if stale.starts_with(&format!("{}-", prefix)) && stale != entry {
+                    let _ = std::fs::remove_file(self.dir.join(&*stale));
+                }
+            }
+            // write then rename, so parallel runs never read half an entry
+            let temp = self.dir.join(format!(
+                "{}.{}-{}.tmp",
+                entry,
+                std::process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::write(&temp, data)?;
+            std::fs::rename(&temp, self.dir.join(entry))
+        })();
+        if let Err(e) = result {
+            eprintln!("Warning: Failed to write startup cache entry {}: {}", entry, e);
+        }
+    }
+}
+
+/// Start of the names of all entries for `path`.
+fn path_prefix(kind: &str, path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    format!("{}-{:016x}", kind, fnv1a(path.to_string_lossy().as_bytes()))
+}
+
+/// Hash of a cache entry's input, also covering the crate version so that
+/// entries written by other builds are never reused.
+fn content_hash(data: &[u8], name: Option<&str>) -> u64 {
+    let parts: [&[u8]; 5] = [env!("CARGO_PKG_VERSION").as_bytes(), b"\0", name.unwrap_or("").as_bytes(), b"\0", data];
+    parts.iter().fold(FNV_OFFSET_BASIS, |hash, part| fnv1a_extend(hash, part))
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across builds.
+fn fnv1a(data: &[u8]) -> u64 {
+    fnv1a_extend(FNV_OFFSET_BASIS, data)
+}
+
+fn fnv1a_extend(hash: u64, data: &[u8]) -> u64 {
+    data.iter()
+        .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_chunk_cache_invalidates_on_change() {
+        let dir = TempDir::new("cache");
+        let cache = StartupCache::new(dir.path());
+        // binary chunks can only be loaded by an unsafe VM, like the simulator's
+        let lua = unsafe { Lua::unsafe_new() };
+        let path = Path::new("Scripts/Test.lua");
+
+        let first: i64 = cache.load_chunk(&lua, path, b"return 1", None).unwrap().call(()).unwrap();
+        let cached: i64 = cache.load_chunk(&lua, path, b"return 1", None).unwrap().call(()).unwrap();
+        let changed: i64 = cache.load_chunk(&lua, path, b"return 2", None).unwrap().call(()).unwrap();
+        let entries = std::fs::read_dir(dir.path()).unwrap().count();
+
+        assert_eq!((first, cached, changed), (1, 1, 2));
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn test_save_cache_invalidates_on_change() {
+        let dir = TempDir::new("save-cache");
+        let cache = StartupCache::new(dir.path());
+        let path = Path::new("Saves/Profile1.sav");
+        let decode = |state: &str| {
+            let state = state.as_bytes().to_vec();
+            move || -> Result<Vec<u8>, Error> { Ok(state) }
+        };
+
+        let first = cache.save_state(path, b"save 1", decode("state 1")).unwrap();
+        let cached = cache.save_state(path, b"save 1", || Err(Error::from("decoded again".to_string()))).unwrap();
+        let changed = cache.save_state(path, b"save 2", decode("state 2")).unwrap();
+        let entries = std::fs::read_dir(dir.path()).unwrap().count();
+
+        assert_eq!((first, cached, changed), (b"state 1".to_vec(), b"state 1".to_vec(), b"state 2".to_vec()));
+        assert_eq!(entries, 1);
+    }
+}
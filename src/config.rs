@@ -0,0 +1,236 @@
+use crate::error::Error;
+use crate::lua_vars::{self, LuaVar};
+use serde::Deserialize;
+use serde_json::Value as Json;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "routefinder.toml";
+
+/// Environment variable naming the profile to use when none is given.
+pub const PROFILE_ENV: &str = "ROUTEFINDER_PROFILE";
+
+/// Environment variable overriding the configured scripts directory.
+pub const SCRIPTS_DIR_ENV: &str = "HADES_SCRIPTS_DIR";
+
+/// Defaults for options left off the command line, from the top level of a
+/// config file or one of its `[profile.NAME]` tables. Relative paths are
+/// relative to the config file and `~/` is the home directory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub scripts_dir: Option<PathBuf>,
+    pub mods_dir: Option<PathBuf>,
+    /// The helper library `Import` searches after the script's directory.
+    pub utils_dir: Option<PathBuf>,
+    /// Extra directories searched by `Import`.
+    pub lib_dirs: Vec<PathBuf>,
+    pub save_file: Option<PathBuf>,
+    /// Script `run` uses when none is given.
+    pub script: Option<PathBuf>,
+    /// Set before any vars file or `--lua-var`.
+    pub lua_vars: Vec<LuaVar>,
+    /// `reverse-rng` method.
+    pub search_backend: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProfile {
+    scripts_dir: Option<String>,
+    mods_dir: Option<String>,
+    utils_dir: Option<String>,
+    lib_dirs: Option<Vec<String>>,
+    save_file: Option<String>,
+    script: Option<String>,
+    lua_vars: Option<Json>,
+    search_backend: Option<String>,
+}
+
+/// The settings of the config files that apply, with a profile selected.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub profile: Profile,
+    /// Files read, lowest precedence first.
+    pub files: Vec<PathBuf>,
+}
+
+impl Config {
+    /// Read the user's `routefinder/routefinder.toml` in the XDG config
+    /// directory, then the nearest `routefinder.toml` in the current
+    /// directory or its parents, whose settings win. The profile is
+    /// `profile` or else `$ROUTEFINDER_PROFILE`, and `$HADES_SCRIPTS_DIR`
+    /// overrides the configured scripts directory.
+    pub fn discover(profile: Option<&str>) -> Result<Config, Error> {
+        let mut files = Vec::new();
+        if let Some(dir) = user_config_dir() {
+            let path = dir.join("routefinder").join(CONFIG_FILE);
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        let cwd = std::env::current_dir()?;
+        if let Some(path) = cwd.ancestors().map(|dir| dir.join(CONFIG_FILE)).find(|path| path.is_file()) {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+        Config::from_env(files, profile)
+    }
+
+    /// Read only `path`, still honoring the environment like [`discover`](Config::discover).
+    pub fn from_file<P: Into<PathBuf>>(path: P, profile: Option<&str>) -> Result<Config, Error> {
+        Config::from_env(vec![path.into()], profile)
+    }
+
+    fn from_env(files: Vec<PathBuf>, profile: Option<&str>) -> Result<Config, Error> {
+        let env_profile = std::env::var(PROFILE_ENV).ok().filter(|name| !name.is_empty());
+        let mut config = Config::load(files, profile.or(env_profile.as_deref()))?;
+        if let Some(dir) = std::env::var_os(SCRIPTS_DIR_ENV).filter(|dir| !dir.is_empty()) {
+            config.profile.scripts_dir = Some(expand_home(&PathBuf::from(dir)));
+        }
+        Ok(config)
+    }
+
+    /// Merge `files`, later ones winning, applying `profile` over each
+    /// file's top-level settings. A profile no file defines is an error.
+    pub fn load(files: Vec<PathBuf>, profile: Option<&str>) -> Result<Config, Error> {
+        let mut merged = Profile::default();
+        let mut found_profile = false;
+        for file in &files {
+            let mut table = std::fs::read_to_string(file)?
+                .parse::<toml::Table>()
+                .map_err(|e| invalid(file, e.to_string()))?;
+            let mut profiles = match table.remove("profile") {
+                Some(toml::Value::Table(profiles)) => profiles,
+                Some(_) => return Err(invalid(file, "profile must be a table of profiles".to_string())),
+                None => toml::Table::new(),
+            };
+
+            merged = merged.overridden_by(read_profile(file, toml::Value::Table(table))?);
+            if let Some(selected) = profile.and_then(|name| profiles.remove(name)) {
+                merged = merged.overridden_by(read_profile(file, selected)?);
+                found_profile = true;
+            }
+        }
+
+        match profile {
+            Some(name) if !found_profile => {
+                let searched: Vec<String> = files.iter().map(|file| file.display().to_string()).collect();
+                Err(Error::from(format!(
+                    "No profile '{}' in {}",
+                    name,
+                    if searched.is_empty() { "any config file".to_string() } else { searched.join(", ") }
+                )))
+            }
+            _ => Ok(Config { profile: merged, files }),
+        }
+    }
+}
+
+impl Profile {
+    /// The discovered default profile, or an empty one with a warning if
+    /// the config can't be read; for the GUIs, which have no other way to
+    /// report it.
+    pub fn discover_or_default() -> Profile {
+        Config::discover(None).map(|config| config.profile).unwrap_or_else(|e| {
+            eprintln!("Warning: Ignoring config: {}", e);
+            Profile::default()
+        })
+    }
+
+    fn overridden_by(self, over: Profile) -> Profile {
+        let mut lua_vars = self.lua_vars;
+        lua_vars.extend(over.lua_vars);
+        let mut lib_dirs = self.lib_dirs;
+        lib_dirs.extend(over.lib_dirs);
+        Profile {
+            scripts_dir: over.scripts_dir.or(self.scripts_dir),
+            mods_dir: over.mods_dir.or(self.mods_dir),
+            utils_dir: over.utils_dir.or(self.utils_dir),
+            lib_dirs,
+            save_file: over.save_file.or(self.save_file),
+            script: over.script.or(self.script),
+            lua_vars,
+            search_backend: over.search_backend.or(self.search_backend),
+        }
+    }
+}
+
+fn read_profile(file: &Path, value: toml::Value) -> Result<Profile, Error> {
+    let raw: RawProfile = value.try_into().map_err(|e| invalid(file, e.to_string()))?;
+    let base = file.parent().unwrap_or_else(|| Path::new(""));
+    let resolve = |path: String| base.join(expand_home(Path::new(&path)));
+    let path = |path: Option<String>| path.map(resolve);
+    Ok(Profile {
+        scripts_dir: path(raw.scripts_dir),
+        mods_dir: path(raw.mods_dir),
+        utils_dir: path(raw.utils_dir),
+        lib_dirs: raw.lib_dirs.unwrap_or_default().into_iter().map(resolve).collect(),
+        save_file: path(raw.save_file),
+        script: path(raw.script),
+        lua_vars: match raw.lua_vars {
+            Some(vars) => lua_vars::from_json(vars).map_err(|e| invalid(file, format!("lua_vars: {}", e)))?,
+            None => Vec::new(),
+        },
+        search_backend: raw.search_backend,
+    })
+}
+
+fn invalid(file: &Path, e: String) -> Error {
+    Error::from(format!("Invalid config file {}: {}", file.display(), e))
+}
+
+/// Replace a leading `~` with the home directory.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn user_config_dir() -> Option<PathBuf> {
+    let env_dir = |name: &str| std::env::var_os(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+    env_dir("XDG_CONFIG_HOME")
+        .or_else(|| env_dir("HOME").map(|home| home.join(".config")))
+        .or_else(|| env_dir("APPDATA"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_profiles_override_defaults() {
+        let dir = TempDir::new("config");
+        let user = dir.join("user.toml");
+        let project = dir.join(CONFIG_FILE);
+        std::fs::write(&user, "scripts_dir = \"/hades/Scripts\"\nsearch_backend = \"brute-force\"\n").unwrap();
+        std::fs::write(
+            &project,
+            r#"
+save_file = "FreshFile.sav"
+lua_vars = { Seed = 1 }
+
+[profile.modded]
+mods_dir = "Mods"
+lua_vars = { Config = { Chamber = 5 } }
+"#,
+        )
+        .unwrap();
+
+        let plain = Config::load(vec![user.clone(), project.clone()], None).unwrap().profile;
+        let modded = Config::load(vec![user.clone(), project.clone()], Some("modded")).unwrap().profile;
+        let missing = Config::load(vec![user, project], Some("speedrun"));
+        std::fs::write(dir.join("typo.toml"), "scripts_dri = \"Scripts\"").unwrap();
+        let typo = Config::load(vec![dir.join("typo.toml")], None);
+
+        assert_eq!(plain.scripts_dir, Some(PathBuf::from("/hades/Scripts")));
+        assert_eq!(plain.save_file, Some(dir.join("FreshFile.sav")));
+        assert_eq!(plain.mods_dir, None);
+        assert_eq!(modded.mods_dir, Some(dir.join("Mods")));
+        let vars: Vec<String> = modded.lua_vars.iter().map(LuaVar::path_string).collect();
+        assert_eq!(vars, ["Seed", "Config.Chamber"]);
+        assert!(missing.is_err());
+        assert!(typo.is_err());
+    }
+}
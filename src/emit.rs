@@ -0,0 +1,122 @@
+use crate::error::Error;
+use mlua::{Table, Value};
+use serde::Serialize;
+use serde_json::Value as Json;
+use std::ffi::c_void;
+use std::str::FromStr;
+
+/// How `run` reports what scripts `Emit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Emitted records are printed as they come, mixed with `print` output.
+    Text,
+    /// One JSON array of all records, printed once the script finishes.
+    Json,
+    /// One JSON object per line, printed as records are emitted.
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err(Error::from(format!("Invalid output format '{}': expected text, json or jsonl", s))),
+        }
+    }
+}
+
+/// One call to `Emit(name, data)`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EmitRecord {
+    pub name: String,
+    pub data: Json,
+}
+
+/// Convert a Lua value to JSON. Tables whose keys are exactly `1..n` become
+/// arrays and other tables objects, with non-string keys written as
+/// strings. Values with no JSON form are written as their type name.
+pub fn to_json(value: &Value) -> Result<Json, String> {
+    write_json(value, &mut Vec::new(), usize::MAX)
+}
+
+/// Like [`to_json`], but tables nested deeper than `max_depth` are written
+/// as `"{...}"`, for logging values that may reach the whole game state.
+pub fn to_json_limited(value: &Value, max_depth: usize) -> Result<Json, String> {
+    write_json(value, &mut Vec::new(), max_depth)
+}
+
+fn write_json(value: &Value, parents: &mut Vec<*const c_void>, max_depth: usize) -> Result<Json, String> {
+    Ok(match value {
+        Value::Nil => Json::Null,
+        Value::Boolean(b) => Json::Bool(*b),
+        Value::Integer(i) => Json::from(*i),
+        // NaN and infinities have no JSON form and become null
+        Value::Number(n) => serde_json::Number::from_f64(*n).map_or(Json::Null, Json::Number),
+        Value::String(s) => Json::from(s.to_string_lossy().into_owned()),
+        Value::Table(_) if parents.len() >= max_depth => Json::from("{...}"),
+        Value::Table(table) => table_to_json(table, parents, max_depth)?,
+        other => Json::from(other.type_name()),
+    })
+}
+
+fn table_to_json(table: &Table, parents: &mut Vec<*const c_void>, max_depth: usize) -> Result<Json, String> {
+    if parents.contains(&table.to_pointer()) {
+        return Err("can't convert a table that contains itself".to_string());
+    }
+    parents.push(table.to_pointer());
+
+    let entries: Vec<(Value, Value)> = table.clone().pairs().collect::<mlua::Result<_>>().map_err(|e| e.to_string())?;
+    let is_array = !entries.is_empty()
+        && entries.iter().all(|(key, _)| matches!(key, Value::Integer(i) if *i >= 1 && *i as usize <= entries.len()));
+    let json = if is_array {
+        let mut items = vec![Json::Null; entries.len()];
+        for (key, value) in &entries {
+            if let Value::Integer(i) = key {
+                items[*i as usize - 1] = write_json(value, parents, max_depth)?;
+            }
+        }
+        Json::Array(items)
+    } else {
+        let mut object = serde_json::Map::new();
+        for (key, value) in &entries {
+            let key = match key {
+                Value::String(s) => s.to_string_lossy().into_owned(),
+                Value::Integer(i) => i.to_string(),
+                Value::Number(n) => n.to_string(),
+                Value::Boolean(b) => b.to_string(),
+                other => other.type_name().to_string(),
+            };
+            object.insert(key, write_json(value, parents, max_depth)?);
+        }
+        Json::Object(object)
+    };
+
+    parents.pop();
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mlua::Lua;
+
+    #[test]
+    fn test_to_json() {
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(r#"return { Rooms = { "A_Combat01", "A_Combat08A" }, Seed = 906036749, [3] = true, Empty = {} }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            to_json(&value).unwrap(),
+            serde_json::json!({ "Rooms": ["A_Combat01", "A_Combat08A"], "Seed": 906036749, "3": true, "Empty": {} })
+        );
+
+        let cyclic: Value = lua.load("local t = {} t.Self = t return t").eval().unwrap();
+        assert!(to_json(&cyclic).is_err());
+    }
+}
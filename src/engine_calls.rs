@@ -0,0 +1,172 @@
+use crate::emit;
+use crate::error::Error;
+use crate::lua_vars::{self, Key, LuaVar};
+use mlua::{Function, Lua, MultiValue};
+use serde::Serialize;
+use serde_json::Value as Json;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// Arguments are logged down to this many levels of tables.
+const ARG_DEPTH: usize = 4;
+
+/// One call the scripts made to an engine function.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EngineCall {
+    pub name: String,
+    pub args: Vec<Json>,
+    /// `file:line` of the caller.
+    pub location: Option<String>,
+}
+
+/// An undefined global the game scripts read, most likely an engine
+/// function `Engine.lua` doesn't stub.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MissingGlobal {
+    pub reads: usize,
+    pub first_location: Option<String>,
+}
+
+/// An engine function defined from Rust, written `NAME=VALUE` like a
+/// `--lua-var`: it records its calls and returns `VALUE`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EngineStub {
+    pub name: String,
+    pub returns: Json,
+}
+
+impl std::str::FromStr for EngineStub {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let var: LuaVar = s.parse()?;
+        match var.path.as_slice() {
+            [Key::Name(name)] => Ok(EngineStub { name: name.clone(), returns: var.value }),
+            _ => Err(Error::from(format!("Invalid engine stub '{}': expected a global function name", s))),
+        }
+    }
+}
+
+/// Calls to engine functions and reads of undefined globals, in order.
+#[derive(Debug, Default)]
+pub struct EngineRecorder {
+    calls: Vec<EngineCall>,
+    missing: BTreeMap<String, MissingGlobal>,
+}
+
+impl EngineRecorder {
+    /// The calls recorded since the last call.
+    pub fn take_calls(&mut self) -> Vec<EngineCall> {
+        std::mem::take(&mut self.calls)
+    }
+
+    pub fn calls(&self) -> &[EngineCall] {
+        &self.calls
+    }
+
+    /// Undefined globals read by the game scripts, by name.
+    pub fn missing(&self) -> &BTreeMap<String, MissingGlobal> {
+        &self.missing
+    }
+
+    pub(crate) fn record_missing(&mut self, name: String, location: Option<String>) {
+        self.missing
+            .entry(name)
+            .or_insert(MissingGlobal { reads: 0, first_location: location })
+            .reads += 1;
+    }
+}
+
+/// `file:line` of the Lua function calling the running Rust function.
+pub(crate) fn caller_location(lua: &Lua) -> Option<String> {
+    // level 0 is the Rust function itself
+    let caller = lua.inspect_stack(1)?;
+    let source = caller.source();
+    Some(format!("{}:{}", source.short_src?, caller.curr_line()))
+}
+
+/// Replace the global function `name` with one recording its calls into
+/// `recorder`, then returning `returns` if given or calling the original.
+pub(crate) fn record_calls(
+    lua: &Lua,
+    recorder: &Rc<RefCell<EngineRecorder>>,
+    name: &str,
+    returns: Option<Json>,
+) -> mlua::Result<()> {
+    let original = match lua.globals().get::<_, Option<Function>>(name)? {
+        Some(original) if returns.is_none() => Some(lua.create_registry_value(original)?),
+        _ => None,
+    };
+    let recorder = recorder.clone();
+    let call_name = name.to_string();
+    let wrapper = lua.create_function(move |lua, args: MultiValue| {
+        let call = EngineCall {
+            name: call_name.clone(),
+            args: args
+                .iter()
+                .map(|arg| emit::to_json_limited(arg, ARG_DEPTH).unwrap_or_else(Json::from))
+                .collect(),
+            location: caller_location(lua),
+        };
+        recorder.borrow_mut().calls.push(call);
+        match (&original, &returns) {
+            (Some(original), _) => lua.registry_value::<Function>(original)?.call::<_, MultiValue>(args),
+            (None, Some(returns)) => Ok(MultiValue::from_vec(vec![lua_vars::json_to_lua(lua, returns)?])),
+            (None, None) => Ok(MultiValue::new()),
+        }
+    })?;
+    lua.globals().set(name, wrapper)
+}
+
+/// Add `GetEngineCalls([name])`, returning the recorded calls (to `name`
+/// only, if given) as `{ Name, Args, Location }` tables.
+pub(crate) fn register(lua: &Lua, recorder: &Rc<RefCell<EngineRecorder>>) -> mlua::Result<()> {
+    let recorder = recorder.clone();
+    let get_engine_calls = lua.create_function(move |lua, name: Option<String>| {
+        let calls = lua.create_table()?;
+        for call in recorder.borrow().calls.iter().filter(|call| name.as_ref().is_none_or(|name| *name == call.name)) {
+            let entry = lua.create_table()?;
+            entry.set("Name", call.name.as_str())?;
+            entry.set("Args", lua_vars::json_to_lua(lua, &Json::Array(call.args.clone()))?)?;
+            entry.set("Location", call.location.as_deref())?;
+            calls.push(entry)?;
+        }
+        Ok(calls)
+    })?;
+    lua.globals().set("GetEngineCalls", get_engine_calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_calls_and_stubs() {
+        let lua = Lua::new();
+        let recorder = Rc::new(RefCell::new(EngineRecorder::default()));
+        lua.load("function SetAnimation(args) return 'original' end").exec().unwrap();
+        record_calls(&lua, &recorder, "SetAnimation", None).unwrap();
+        let stub: EngineStub = "GetConfigOptionValue=5".parse().unwrap();
+        record_calls(&lua, &recorder, &stub.name, Some(stub.returns)).unwrap();
+        register(&lua, &recorder).unwrap();
+
+        let (original, stubbed, recorded): (String, i64, usize) = lua
+            .load(
+                r#"
+                local original = SetAnimation({ Name = "ZagreusIdle", DestinationId = 40000 })
+                local stubbed = GetConfigOptionValue({ Name = "DebugRNGSeed" })
+                return original, stubbed, #GetEngineCalls("SetAnimation")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((original.as_str(), stubbed, recorded), ("original", 5, 1));
+        let calls = recorder.borrow_mut().take_calls();
+        assert_eq!(calls[0].args, vec![serde_json::json!({ "Name": "ZagreusIdle", "DestinationId": 40000 })]);
+        assert_eq!(calls[1].name, "GetConfigOptionValue");
+        assert!(calls[1].location.as_deref().unwrap_or("").ends_with(":3"));
+
+        assert!("Config.Seed=1".parse::<EngineStub>().is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::limits::LimitExceeded;
 use mlua;
 use std::sync::Arc;
 
@@ -20,6 +21,7 @@ impl std::fmt::Display for Error {
             Error::Lua { error } => write!(f, "Lua error: {}", error),
             Error::IO { error } => write!(f, "IO error: {}", error),
             Error::SimpleString { error } => write!(f, "{}", error),
+            Error::Limit { error } => write!(f, "{}", error),
         }
     }
 }
@@ -31,11 +33,15 @@ pub enum Error {
     Lua { error: mlua::Error },
     IO { error: std::io::Error },
     SimpleString { error: SimpleStringError },
+    Limit { error: LimitExceeded },
 }
 
 impl From<mlua::Error> for Error {
     fn from(error: mlua::Error) -> Self {
-        Error::Lua { error: error }
+        match LimitExceeded::find(&error) {
+            Some(exceeded) => Error::Limit { error: exceeded.clone() },
+            None => Error::Lua { error: error },
+        }
     }
 }
 
@@ -45,6 +51,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<LimitExceeded> for Error {
+    fn from(error: LimitExceeded) -> Self {
+        Error::Limit { error }
+    }
+}
+
 impl From<String> for Error {
     fn from(description: String) -> Self {
         Error::SimpleString {
@@ -61,6 +73,7 @@ impl From<Error> for mlua::Error {
             Error::Lua { error } => error,
             Error::IO { error } => mlua::Error::ExternalError(Arc::new(error)),
             Error::SimpleString { error } => mlua::Error::ExternalError(Arc::new(error)),
+            Error::Limit { error } => mlua::Error::ExternalError(Arc::new(error)),
         }
     }
 }
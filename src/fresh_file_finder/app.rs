@@ -1,4 +1,6 @@
 use std::sync::Arc;
+use crate::config::Profile;
+use crate::gui::{DEFAULT_SAVE_FILE, DEFAULT_SCRIPTS_DIR};
 use druid::{Data, Lens};
 
 #[derive(Clone, Data, PartialEq)]
@@ -22,6 +24,9 @@ pub struct AppState {
     pub off_route_chamber: String,
     pub offset_off_by: i32,
     pub original_script_file: String,
+
+    // Config profile, passed on to every routefinder run
+    pub profile: Arc<Profile>,
 }
 
 #[derive(Clone, Data)]
@@ -32,18 +37,21 @@ pub struct ButtonPress {
 
 impl Default for AppState {
     fn default() -> Self {
+        let config = Profile::discover_or_default();
+        let script = config.script.as_ref().map_or_else(|| "RouteFreshFileIncrementally.lua".to_string(), |path| path.display().to_string());
         Self {
             offset: 6,
             button_history: Arc::new(Vec::new()),
             text_output: "Current offset: 6\n".to_string(),
-            save_file_path: "FreshFile.sav".to_string(),
-            scripts_dir_path: "~/workspace/hades/routefinder/assets/Scripts/".to_string(),
-            script_file: "RouteFreshFileIncrementally.lua".to_string(),
+            save_file_path: config.save_file.as_ref().map_or_else(|| DEFAULT_SAVE_FILE.to_string(), |path| path.display().to_string()),
+            scripts_dir_path: config.scripts_dir.as_ref().map_or_else(|| DEFAULT_SCRIPTS_DIR.to_string(), |path| path.display().to_string()),
+            script_file: script.clone(),
             found_seed: None,
             mode: Mode::BounceRoute,
             off_route_chamber: String::new(),
             offset_off_by: 0,
-            original_script_file: "RouteFreshFileIncrementally.lua".to_string(),
+            original_script_file: script,
+            profile: Arc::new(config),
         }
     }
 }
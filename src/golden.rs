@@ -0,0 +1,201 @@
+use crate::error::Error;
+use crate::simulator::{CapturedOutput, SimulatorBuilder};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Extension of the files holding a script's expected output.
+pub const TEST_EXTENSION: &str = "test";
+
+/// Unchanged lines shown around each change in a diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// Most cells of the table `diff` builds for the changed lines; bigger
+/// changes are only summarized.
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+/// A script and the file with the output it should print.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoldenTest {
+    pub name: String,
+    pub script: PathBuf,
+    pub expected: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// The output differs from the expected output, as shown by `diff`.
+    Failed { diff: String },
+    /// The script raised an error or couldn't be run.
+    Error { message: String },
+    /// The expected output was replaced with the script's.
+    Blessed,
+}
+
+/// Pair every `NAME.test` in `test_dir` with the script `NAME.lua` in
+/// `script_dir`, sorted by name. With `names`, only those tests, which
+/// must exist.
+pub fn find_tests(test_dir: &Path, script_dir: &Path, names: &[String]) -> Result<Vec<GoldenTest>, Error> {
+    let test = |name: &str| GoldenTest {
+        name: name.to_string(),
+        script: script_dir.join(format!("{}.lua", name)),
+        expected: test_dir.join(format!("{}.{}", name, TEST_EXTENSION)),
+    };
+    if !names.is_empty() {
+        return names
+            .iter()
+            .map(|name| match test(name) {
+                test if test.expected.is_file() => Ok(test),
+                test => Err(Error::from(format!("No expected output {} for test {}", test.expected.display(), name))),
+            })
+            .collect();
+    }
+
+    let mut tests = Vec::new();
+    for entry in std::fs::read_dir(test_dir)
+        .map_err(|e| Error::from(format!("Can't read test directory {}: {}", test_dir.display(), e)))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == TEST_EXTENSION) {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                tests.push(test(name));
+            }
+        }
+    }
+    tests.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tests)
+}
+
+/// Run each test's script on one simulation, restored to the loaded state
+/// before each, and compare what it prints and emits with the expected
+/// output. With `bless`, write the output as the new expectation instead.
+pub fn run_tests(
+    tests: &[GoldenTest],
+    builder: SimulatorBuilder,
+    bless: bool,
+    mut on_outcome: impl FnMut(&GoldenTest, &Outcome),
+) -> Result<Vec<Outcome>, Error> {
+    let output = CapturedOutput::default();
+    let mut emitted = output.clone();
+    let sim = builder
+        .output(output.clone())
+        .on_emit(move |record| {
+            let data = serde_json::to_string_pretty(&record.data).unwrap_or_default();
+            let _ = writeln!(emitted, "{} {}", record.name, data);
+        })
+        .build()?;
+    let snapshot = sim.snapshot()?;
+
+    let mut outcomes = Vec::new();
+    for test in tests {
+        sim.restore(&snapshot)?;
+        sim.reset_limits();
+        let outcome = match sim.run_file(&test.script) {
+            Err(e) => Outcome::Error { message: format!("{}{}", output.take(), e) },
+            Ok(()) if bless => {
+                std::fs::write(&test.expected, output.take())?;
+                Outcome::Blessed
+            }
+            Ok(()) => {
+                let expected = std::fs::read_to_string(&test.expected)?.replace("\r\n", "\n");
+                let actual = output.take();
+                if expected.trim_end_matches('\n') == actual.trim_end_matches('\n') {
+                    Outcome::Passed
+                } else {
+                    Outcome::Failed { diff: diff(&expected, &actual) }
+                }
+            }
+        };
+        on_outcome(test, &outcome);
+        outcomes.push(outcome);
+    }
+    Ok(outcomes)
+}
+
+/// A line diff from `expected` to `actual` in unified format, with
+/// `DIFF_CONTEXT` lines around each change, or just the line counts if
+/// the changed part is too big to compare.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let prefix = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (changed_a, changed_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if (changed_a.len() + 1).saturating_mul(changed_b.len() + 1) > MAX_DIFF_CELLS {
+        return format!("outputs differ, {} vs {} lines, first at line {}\n", a.len(), b.len(), prefix + 1);
+    }
+
+    // lcs[i][j] is the longest common subsequence of changed_a[i..] and changed_b[j..]
+    let mut lcs = vec![vec![0usize; changed_b.len() + 1]; changed_a.len() + 1];
+    for i in (0..changed_a.len()).rev() {
+        for j in (0..changed_b.len()).rev() {
+            lcs[i][j] = if changed_a[i] == changed_b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    // (prefix, line, line number in expected, line number in actual)
+    let mut lines: Vec<(char, &str, usize, usize)> = (0..prefix).map(|i| (' ', a[i], i, i)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < changed_a.len() || j < changed_b.len() {
+        if i < changed_a.len() && j < changed_b.len() && changed_a[i] == changed_b[j] {
+            lines.push((' ', changed_a[i], prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        } else if i < changed_a.len() && (j == changed_b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', changed_a[i], prefix + i, prefix + j));
+            i += 1;
+        } else {
+            lines.push(('+', changed_b[j], prefix + i, prefix + j));
+            j += 1;
+        }
+    }
+    let (suffix_a, suffix_b) = (a.len() - suffix, b.len() - suffix);
+    lines.extend((0..suffix).map(|k| (' ', a[suffix_a + k], suffix_a + k, suffix_b + k)));
+
+    let changed: Vec<usize> = (0..lines.len()).filter(|&k| lines[k].0 != ' ').collect();
+    let mut out = String::new();
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(DIFF_CONTEXT);
+        let mut end = changed[k] + 1;
+        while k < changed.len() && changed[k] <= end + 2 * DIFF_CONTEXT {
+            end = changed[k] + 1;
+            k += 1;
+        }
+        let end = (end + DIFF_CONTEXT).min(lines.len());
+        let hunk = &lines[start..end];
+        let count = |prefix: char| hunk.iter().filter(|line| line.0 == ' ' || line.0 == prefix).count();
+        let (_, _, first_a, first_b) = hunk[0];
+        out += &format!("@@ -{},{} +{},{} @@\n", first_a + 1, count('-'), first_b + 1, count('+'));
+        for (prefix, line, _, _) in hunk {
+            out += &format!("{}{}\n", prefix, line);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_shows_changes_with_context() {
+        let expected = "C1\n  Seed\t1\n  Type\tHammer\nC2\n  Room\tA_Combat01\n";
+        let actual = "C1\n  Seed\t1\n  Type\tBoon\nC2\n  Room\tA_Combat01\n  Exits\t2\n";
+        assert_eq!(
+            diff(expected, actual),
+            "@@ -1,5 +1,6 @@\n C1\n   Seed\t1\n-  Type\tHammer\n+  Type\tBoon\n C2\n   Room\tA_Combat01\n+  Exits\t2\n"
+        );
+        assert_eq!(diff(expected, expected), "");
+
+        let long: String = (0..5000).map(|i| format!("{}\n", i)).collect();
+        let one_change = diff(&long, &long.replace("\n2500\n", "\n2500!\n"));
+        assert!(one_change.starts_with("@@ -2498,7 +2498,7 @@\n 2497\n"));
+        assert_eq!(one_change.lines().count(), 9);
+        let other: String = (0..5000).map(|i| format!("{}?\n", i)).collect();
+        assert_eq!(diff(&long, &other), "outputs differ, 5000 vs 5000 lines, first at line 1\n");
+    }
+}
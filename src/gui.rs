@@ -0,0 +1,77 @@
+use crate::config::Profile;
+use druid::{ExtEventSink, Selector, Target};
+use std::io::{BufRead, BufReader};
+use std::process::Child;
+use std::thread::JoinHandle;
+
+// Events the GUIs' background threads send to the window
+pub const OUTPUT_UPDATE: Selector<String> = Selector::new("output-update");
+pub const CALCULATION_COMPLETE: Selector<()> = Selector::new("calculation-complete");
+pub const CALCULATION_ERROR: Selector<String> = Selector::new("calculation-error");
+
+/// Save file the GUIs use when the config doesn't name one.
+pub const DEFAULT_SAVE_FILE: &str = "FreshFile.sav";
+
+/// Scripts directory the GUIs use when the config doesn't name one.
+pub const DEFAULT_SCRIPTS_DIR: &str = "~/workspace/hades/routefinder/assets/Scripts/";
+
+/// Show what `child` writes to stderr, like script errors and their
+/// tracebacks, while the caller reads its stdout.
+pub fn forward_stderr(child: &mut Child, event_sink: &ExtEventSink) -> JoinHandle<()> {
+    let stderr = child.stderr.take().unwrap();
+    let event_sink = event_sink.clone();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(|line| line.ok()) {
+            event_sink.submit_command(OUTPUT_UPDATE, format!("{}\n", line), Target::Auto).ok();
+        }
+    })
+}
+
+/// Wait for `child` to exit, reporting a calculation error unless it
+/// succeeded.
+pub fn wait_for_success(mut child: Child, stderr: JoinHandle<()>, event_sink: &ExtEventSink) -> bool {
+    let status = child.wait();
+    stderr.join().ok();
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            event_sink.submit_command(CALCULATION_ERROR, format!("routefinder failed ({})", status), Target::Auto).ok();
+            false
+        }
+        Err(e) => {
+            event_sink.submit_command(CALCULATION_ERROR, e.to_string(), Target::Auto).ok();
+            false
+        }
+    }
+}
+
+/// Arguments for a `routefinder run` the GUIs start so that it uses
+/// `profile` as they resolved it, rather than discovering the config again.
+/// The save file and scripts directory, which the GUIs show, are up to the
+/// caller.
+pub fn run_args(profile: &Profile) -> Vec<String> {
+    let mut args = vec!["--no-config".to_string()];
+    if let Some(mods_dir) = &profile.mods_dir {
+        args.extend(["--mods-dir".to_string(), mods_dir.display().to_string()]);
+    }
+    if let Some(utils_dir) = &profile.utils_dir {
+        args.extend(["--utils-dir".to_string(), utils_dir.display().to_string()]);
+    }
+    for lib_dir in &profile.lib_dirs {
+        args.extend(["--lib-dir".to_string(), lib_dir.display().to_string()]);
+    }
+    for lua_var in &profile.lua_vars {
+        args.extend(["--lua-var".to_string(), lua_var.to_string()]);
+    }
+    args
+}
+
+/// Arguments for a `routefinder reverse-rng` using `profile`'s search
+/// backend.
+pub fn reverse_rng_args(profile: &Profile) -> Vec<String> {
+    let mut args = vec!["--no-config".to_string()];
+    if let Some(backend) = &profile.search_backend {
+        args.extend(["--method".to_string(), backend.clone()]);
+    }
+    args
+}
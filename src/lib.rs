@@ -1,13 +1,34 @@
 
 #![cfg_attr(feature = "simd_nightly", feature(stdarch_x86_avx512))]
 
+pub mod cache;
+pub mod config;
+pub mod emit;
+pub mod engine_calls;
 pub mod error;
 pub mod fresh_file_finder;
 pub mod sack_finder;
+pub mod golden;
+pub mod gui;
+pub mod limits;
+pub mod lua_rng;
+pub mod lua_vars;
 pub mod luabins;
+pub mod mods;
+pub mod profiler;
 pub mod read;
+pub mod repl;
 pub mod write;
 pub mod rng;
+pub mod rng_draw;
+pub mod rng_slots;
+pub mod rng_trace;
+pub mod sandbox;
 pub mod save;
+pub mod scan;
+pub mod simulator;
 pub mod reverse_rng;
 
+#[cfg(test)]
+mod temp_dir;
+
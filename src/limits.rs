@@ -0,0 +1,222 @@
+use crate::rng_trace::lua_stack_from;
+use mlua::Lua;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Limits are checked every this many VM instructions, from the
+/// simulator's instruction hook, so that's how precisely
+/// `max_instructions` is kept.
+pub const CHECK_INTERVAL: u32 = 1000;
+
+/// The allocator refuses memory this fraction past `max_memory`, leaving
+/// room for the hook to catch gradual growth with the stack intact; only
+/// a single huge allocation runs into the allocator.
+const MEMORY_HEADROOM: usize = 16;
+
+/// Frames of the Lua stack kept in a [`LimitExceeded`].
+const STACK_DEPTH: usize = 20;
+
+/// How long and how far the scripts may run before they're stopped.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub max_instructions: Option<u64>,
+    /// Bytes used by the whole VM, game scripts included.
+    pub max_memory: Option<usize>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// The limit a script ran into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Timeout(Duration),
+    Instructions(u64),
+    Memory(usize),
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Limit::Timeout(timeout) => write!(f, "timeout of {:?}", timeout),
+            Limit::Instructions(count) => write!(f, "limit of {} instructions", count),
+            Limit::Memory(bytes) => write!(f, "memory limit of {} bytes", bytes),
+        }
+    }
+}
+
+/// The error stopping a script that hit one of its [`Limits`], with the
+/// Lua stack at that moment, innermost first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    /// Empty if the allocator ran out, since the stack is gone by then.
+    pub stack: Vec<String>,
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Script stopped: {} exceeded", self.limit)?;
+        if !self.stack.is_empty() {
+            write!(f, "\nstack traceback:")?;
+            for frame in &self.stack {
+                write!(f, "\n\t{}", frame)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl LimitExceeded {
+    /// The limit error somewhere in `error`'s causes, if any.
+    pub fn find(error: &mlua::Error) -> Option<&LimitExceeded> {
+        match error {
+            mlua::Error::ExternalError(cause) => cause.downcast_ref(),
+            mlua::Error::CallbackError { cause, .. } | mlua::Error::WithContext { cause, .. } => {
+                LimitExceeded::find(cause)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The running budget of a VM with [`Limits`].
+#[derive(Debug)]
+pub(crate) struct LimitState {
+    limits: Limits,
+    started: Cell<Instant>,
+    instructions: Cell<u64>,
+    /// Once a limit is hit it keeps firing, so `pcall` can't carry on past it.
+    exceeded: Cell<Option<Limit>>,
+}
+
+impl LimitState {
+    /// Start enforcing `limits` on `lua`: memory through the allocator
+    /// right away, the rest once the hook calls [`on_instructions`](LimitState::on_instructions).
+    pub(crate) fn new(lua: &Lua, limits: Limits) -> Result<Rc<LimitState>, String> {
+        if let Some(max_memory) = limits.max_memory {
+            let used = lua.used_memory();
+            if used > max_memory {
+                return Err(format!("Memory limit of {} bytes is below the {} the game already uses", max_memory, used));
+            }
+            lua.set_memory_limit(max_memory.saturating_add(max_memory / MEMORY_HEADROOM)).map_err(|e| e.to_string())?;
+        }
+        Ok(Rc::new(LimitState {
+            limits,
+            started: Cell::new(Instant::now()),
+            instructions: Cell::new(0),
+            exceeded: Cell::new(None),
+        }))
+    }
+
+    pub(crate) fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Start the clock and instruction count over.
+    pub(crate) fn reset(&self) {
+        self.started.set(Instant::now());
+        self.instructions.set(0);
+        self.exceeded.set(None);
+    }
+
+    /// Count another [`CHECK_INTERVAL`] instructions and fail with the
+    /// stack if any limit is exceeded.
+    pub(crate) fn on_instructions(&self, lua: &Lua) -> mlua::Result<()> {
+        match self.check(lua) {
+            Some(limit) => {
+                // level 0 is the function the hook interrupted
+                let stack = lua_stack_from(lua, 0, STACK_DEPTH);
+                Err(mlua::Error::external(LimitExceeded { limit, stack }))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check(&self, lua: &Lua) -> Option<Limit> {
+        self.instructions.set(self.instructions.get() + u64::from(CHECK_INTERVAL));
+        let Limits { timeout, max_instructions, max_memory } = self.limits;
+        let exceeded = self
+            .exceeded
+            .get()
+            .or_else(|| timeout.filter(|timeout| self.started.get().elapsed() > *timeout).map(Limit::Timeout))
+            .or_else(|| max_instructions.filter(|max| self.instructions.get() > *max).map(Limit::Instructions))
+            .or_else(|| max_memory.filter(|max| lua.used_memory() > *max).map(Limit::Memory));
+        self.exceeded.set(exceeded);
+        exceeded
+    }
+}
+
+/// Parse a duration like `90`, `1.5s`, `500ms`, `10m` or `2h`; plain
+/// numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}': expected a number of seconds or e.g. 500ms, 30s, 10m, 2h", s);
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+/// Parse a size in bytes like `1048576`, `512K`, `256M` or `2G` (powers
+/// of 1024).
+pub fn parse_bytes(s: &str) -> Result<usize, String> {
+    let invalid = || format!("Invalid size '{}': expected a number of bytes or e.g. 512K, 256M, 2G", s);
+    let s = s.trim();
+    let (number, scale) = match s.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let scale = match unit.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(invalid()),
+            };
+            (&s[..i], scale)
+        }
+        _ => (s, 1),
+    };
+    let number: usize = number.trim().parse().map_err(|_| invalid())?;
+    number.checked_mul(scale).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_stop_scripts_with_stack() {
+        let lua = Lua::new();
+        let state = LimitState::new(&lua, Limits { max_instructions: Some(100_000), ..Limits::default() }).unwrap();
+        lua.set_hook(mlua::HookTriggers::new().every_nth_instruction(CHECK_INTERVAL), move |lua, _debug| {
+            state.on_instructions(lua)
+        });
+        let error = lua
+            .load("function Spin()\n  while true do end\nend\nreturn pcall(Spin) or Spin()")
+            .set_name("@Spin.lua")
+            .exec()
+            .unwrap_err();
+        let exceeded = LimitExceeded::find(&error).unwrap();
+        assert_eq!(exceeded.limit, Limit::Instructions(100_000));
+        assert_eq!(exceeded.stack[0], "Spin.lua:2 in Spin");
+
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("soon").is_err());
+        assert_eq!(parse_bytes("256M"), Ok(256 << 20));
+        assert!(parse_bytes("1T").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::rng::{rand_double, rand_int, GameRng, SggPcg};
+use mlua::{Lua, MetaMethod, UserData, UserDataMethods};
+
+/// A generator scripts own, for looking ahead without touching the
+/// engine's `randomseed` streams:
+///
+/// ```lua
+/// local r = Rng.new(seed)
+/// r:advance(n)
+/// local copy = r:clone()
+/// print(r:int(1, 16), copy:int(1, 16))
+/// ```
+#[derive(Clone, Debug)]
+pub struct LuaRng<R: GameRng = SggPcg>(pub R);
+
+impl<R: GameRng + 'static> UserData for LuaRng<R> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // negative steps go backwards
+        methods.add_method_mut("advance", |_, this, steps: i64| {
+            if steps >= 0 {
+                this.0.advance(steps as u64);
+            } else {
+                this.0.retreat(steps.unsigned_abs());
+            }
+            Ok(())
+        });
+        methods.add_method_mut("int", |_, this, (min, max): (i32, i32)| Ok(rand_int(&mut this.0, min, max)));
+        methods.add_method_mut("float", |_, this, (min, max): (Option<f64>, Option<f64>)| {
+            let (min, max) = (min.unwrap_or(0.0), max.unwrap_or(1.0));
+            Ok(min + rand_double(&mut this.0) * (max - min))
+        });
+        methods.add_method_mut("chance", |_, this, chance: f64| Ok(rand_double(&mut this.0) <= chance));
+        methods.add_method_mut("raw", |_, this, ()| Ok(this.0.next_u32()));
+        methods.add_method("uses", |_, this, ()| Ok(this.0.uses()));
+        methods.add_method("clone", |_, this, ()| Ok(this.clone()));
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+            Ok(format!("Rng(uses {})", this.0.uses()))
+        });
+    }
+}
+
+/// Install the `Rng` table in the Lua globals.
+pub fn register<R: GameRng + 'static>(lua: &Lua) -> mlua::Result<()> {
+    let rng = lua.create_table()?;
+    rng.set(
+        "new",
+        lua.create_function(|_, seed: Option<i64>| Ok(LuaRng(R::new(seed.unwrap_or(0) as u64))))?,
+    )?;
+    lua.globals().set("Rng", rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lua_rng_is_independent_of_clones() {
+        let lua = Lua::new();
+        register::<SggPcg>(&lua).unwrap();
+        let (first, second, uses): (i32, i32, i64) = lua
+            .load(
+                r#"
+                local r = Rng.new(42)
+                r:advance(3)
+                local copy = r:clone()
+                local first = r:int(1, 16)
+                copy:advance(-1)
+                copy:raw()
+                return first, copy:int(1, 16), r:uses()
+                "#,
+            )
+            .eval()
+            .unwrap();
+
+        let mut expected = SggPcg::new(42);
+        expected.advance(3);
+        assert_eq!(first, rand_int(&mut expected.clone(), 1, 16));
+        assert_eq!(second, first);
+        assert_eq!(uses, 4);
+    }
+}
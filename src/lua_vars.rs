@@ -0,0 +1,274 @@
+use crate::error::Error;
+use mlua::{Lua, Table, Value};
+use serde_json::Value as Json;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// One segment of a dotted variable path; digits index arrays.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Key {
+    Name(String),
+    Index(i64),
+}
+
+/// A global to set before running a script, written on the command line as
+/// `PATH[:TYPE]=VALUE`:
+///
+/// - `PATH` is a global name, or a dotted path into tables such as
+///   `Config.Chamber` or `Rooms.1`; missing tables are created.
+/// - `TYPE` is one of `int`, `float`, `bool`, `string` or `json`. Without
+///   it, integers, floats, `true`/`false` and JSON objects and arrays are
+///   recognized and anything else is a string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuaVar {
+    pub path: Vec<Key>,
+    pub value: Json,
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Name(name) => write!(f, "{}", name),
+            Key::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+impl LuaVar {
+    pub fn path_string(&self) -> String {
+        self.path.iter().map(Key::to_string).collect::<Vec<_>>().join(".")
+    }
+
+    /// Assign the value in `lua`'s globals.
+    pub fn apply(&self, lua: &Lua) -> Result<(), Error> {
+        let (last, parents) = self.path.split_last().expect("variable paths are never empty");
+        let mut table = lua.globals();
+        for (depth, key) in parents.iter().enumerate() {
+            let key = key_to_lua(lua, key)?;
+            table = match table.raw_get::<_, Value>(key.clone())? {
+                Value::Table(inner) => inner,
+                Value::Nil => {
+                    let inner = lua.create_table()?;
+                    table.raw_set(key, inner.clone())?;
+                    inner
+                }
+                other => {
+                    let parent: Vec<String> = self.path[..=depth].iter().map(Key::to_string).collect();
+                    return Err(Error::from(format!(
+                        "Can't set {}: {} is a {}, not a table",
+                        self.path_string(),
+                        parent.join("."),
+                        other.type_name()
+                    )));
+                }
+            };
+        }
+        table.raw_set(key_to_lua(lua, last)?, json_to_lua(lua, &self.value)?)?;
+        Ok(())
+    }
+}
+
+/// Written as `PATH:json=VALUE`, which parses back to the same variable.
+impl fmt::Display for LuaVar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:json={}", self.path_string(), self.value)
+    }
+}
+
+impl FromStr for LuaVar {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (target, value) = s
+            .split_once('=')
+            .ok_or_else(|| Error::from(format!("Invalid lua-var '{}': expected PATH=VALUE", s)))?;
+        let (path, kind) = match target.split_once(':') {
+            Some((path, kind)) => (path, Some(kind.trim())),
+            None => (target, None),
+        };
+        let path = parse_path(path.trim()).map_err(|e| Error::from(format!("Invalid lua-var '{}': {}", s, e)))?;
+        let value = parse_value(value, kind).map_err(|e| Error::from(format!("Invalid lua-var '{}': {}", s, e)))?;
+        Ok(LuaVar { path, value })
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Key>, String> {
+    path.split('.')
+        .map(|segment| {
+            if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+                segment.parse().map(Key::Index).map_err(|_| format!("index '{}' is too large", segment))
+            } else if is_identifier(segment) {
+                Ok(Key::Name(segment.to_string()))
+            } else {
+                Err(format!("'{}' is not a valid name", segment))
+            }
+        })
+        .collect()
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_value(value: &str, kind: Option<&str>) -> Result<Json, String> {
+    let json = |value: &str| serde_json::from_str::<Json>(value).map_err(|e| format!("invalid JSON: {}", e));
+    match kind {
+        Some("int") => value.trim().parse::<i64>().map(Json::from).map_err(|_| format!("'{}' is not an int", value)),
+        // JSON has no infinity or NaN, they'd turn into null and so nil
+        Some("float") => match value.trim().parse::<f64>() {
+            Ok(float_val) if float_val.is_finite() => Ok(Json::from(float_val)),
+            Ok(_) => Err(format!("'{}' is not a finite float", value)),
+            Err(_) => Err(format!("'{}' is not a float", value)),
+        },
+        Some("bool") => match value.trim().to_ascii_lowercase().as_str() {
+            "true" => Ok(Json::Bool(true)),
+            "false" => Ok(Json::Bool(false)),
+            _ => Err(format!("'{}' is not a bool", value)),
+        },
+        Some("string") => Ok(Json::from(value)),
+        Some("json") => json(value),
+        Some(other) => Err(format!("unknown type '{}', expected int, float, bool, string or json", other)),
+        None => {
+            if let Ok(int_val) = value.parse::<i64>() {
+                Ok(int_val.into())
+            } else if let Some(float_val) = value.parse::<f64>().ok().filter(|float_val| float_val.is_finite()) {
+                Ok(float_val.into())
+            } else if value.eq_ignore_ascii_case("true") {
+                Ok(Json::Bool(true))
+            } else if value.eq_ignore_ascii_case("false") {
+                Ok(Json::Bool(false))
+            } else if value.trim_start().starts_with(&['{', '['][..]) {
+                json(value)
+            } else {
+                Ok(Json::from(value))
+            }
+        }
+    }
+}
+
+/// Read variables from a TOML file, or JSON if it ends in `.json`. Nested
+/// tables become dotted paths, so `[Config]` `Chamber = 5` is the same as
+/// `--lua-var Config.Chamber=5`; arrays are assigned whole.
+pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Vec<LuaVar>, Error> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let invalid = |e: String| Error::from(format!("Invalid lua vars file {}: {}", path.display(), e));
+    let root = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str::<Json>(&text).map_err(|e| invalid(e.to_string()))?
+    } else {
+        let table = text.parse::<toml::Table>().map_err(|e| invalid(e.to_string()))?;
+        serde_json::to_value(table).map_err(|e| invalid(e.to_string()))?
+    };
+    from_json(root).map_err(invalid)
+}
+
+/// Variables from a table of them, flattened like [`load_file`] does.
+pub fn from_json(root: Json) -> Result<Vec<LuaVar>, String> {
+    let root = match root {
+        Json::Object(root) => root,
+        _ => return Err("expected a table of variables".to_string()),
+    };
+
+    let mut vars = Vec::new();
+    for (name, value) in root {
+        let path = parse_path(&name)?;
+        flatten(path, value, &mut vars);
+    }
+    Ok(vars)
+}
+
+fn flatten(path: Vec<Key>, value: Json, vars: &mut Vec<LuaVar>) {
+    match value {
+        Json::Object(entries) if !entries.is_empty() => {
+            for (name, value) in entries {
+                let mut path = path.clone();
+                path.push(Key::Name(name));
+                flatten(path, value, vars);
+            }
+        }
+        value => vars.push(LuaVar { path, value }),
+    }
+}
+
+fn key_to_lua<'lua>(lua: &'lua Lua, key: &Key) -> mlua::Result<Value<'lua>> {
+    Ok(match key {
+        Key::Name(name) => Value::String(lua.create_string(name)?),
+        Key::Index(index) => Value::Integer(*index),
+    })
+}
+
+/// Convert JSON to Lua: objects become tables, arrays become tables indexed
+/// from 1 and null becomes nil.
+pub fn json_to_lua<'lua>(lua: &'lua Lua, value: &Json) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Boolean(*b),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Json::String(s) => Value::String(lua.create_string(s)?),
+        Json::Array(items) => {
+            let table = lua.create_table()?;
+            for (i, item) in items.iter().enumerate() {
+                table.raw_set(i + 1, json_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        Json::Object(entries) => {
+            let table: Table = lua.create_table()?;
+            for (key, item) in entries {
+                table.raw_set(key.as_str(), json_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lua_var() {
+        let var: LuaVar = "Config.Rooms.2=A_Combat01".parse().unwrap();
+        assert_eq!(var.path_string(), "Config.Rooms.2");
+        assert_eq!(var.path[2], Key::Index(2));
+        assert_eq!(var.value, Json::from("A_Combat01"));
+
+        assert_eq!("Seed:string=123".parse::<LuaVar>().unwrap().value, Json::from("123"));
+        assert_eq!("Odds=0.25".parse::<LuaVar>().unwrap().value, Json::from(0.25));
+        assert_eq!("Need=[1, 2]".parse::<LuaVar>().unwrap().value, serde_json::json!([1, 2]));
+        assert_eq!("Boon=inf".parse::<LuaVar>().unwrap().value, Json::from("inf"));
+        assert_eq!("Boon=NaN".parse::<LuaVar>().unwrap().value, Json::from("NaN"));
+        for var in ["Seed:string=123", "Config.Doors={\"Left\": [\"A\", 2]}"] {
+            let var: LuaVar = var.parse().unwrap();
+            assert_eq!(var.to_string().parse::<LuaVar>().unwrap(), var);
+        }
+
+        assert!("Seed".parse::<LuaVar>().is_err());
+        assert!("Seed:int=abc".parse::<LuaVar>().is_err());
+        assert!("Odds:float=infinity".parse::<LuaVar>().is_err());
+        assert!("Need={1".parse::<LuaVar>().is_err());
+        assert!("Bad-Name=1".parse::<LuaVar>().is_err());
+    }
+
+    #[test]
+    fn test_apply_creates_tables() {
+        let lua = Lua::new();
+        lua.load("Config = { Keep = true }").exec().unwrap();
+        for var in ["Config.Chamber=5", "Config.Doors={\"Left\": [\"A\", \"B\"]}", "Fresh.Depth:float=2"] {
+            var.parse::<LuaVar>().unwrap().apply(&lua).unwrap();
+        }
+        let (keep, chamber, door, depth): (bool, i64, String, f64) = lua
+            .load("return Config.Keep, Config.Chamber, Config.Doors.Left[2], Fresh.Depth")
+            .eval()
+            .unwrap();
+        assert_eq!((keep, chamber, door.as_str(), depth), (true, 5, "B", 2.0));
+
+        assert!("Config.Chamber.Name=x".parse::<LuaVar>().unwrap().apply(&lua).is_err());
+    }
+}
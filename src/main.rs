@@ -1,49 +1,248 @@
-mod error;
-mod luabins;
-mod read;
-mod reverse_rng;
-mod rng;
-mod save;
-mod write;
-use clap::{Parser, Subcommand};
-use libm::ldexp;
-use lz4;
-use mlua::{Lua, LuaOptions, Table, Value, Variadic};
+use clap::{Args, Parser, Subcommand};
 use rand::RngCore;
-use rng::SggPcg;
-use save::UncompressedSize;
-use std::cell::RefCell;
-use std::fs;
+use routefinder::cache;
+use routefinder::config::{Config, Profile};
+use routefinder::emit::{EmitRecord, OutputFormat};
+use routefinder::engine_calls::{EngineCall, EngineStub, MissingGlobal};
+use routefinder::error;
+use routefinder::golden::{self, Outcome};
+use routefinder::limits;
+use routefinder::lua_vars::{self, LuaVar};
+use routefinder::profiler;
+use routefinder::repl;
+use routefinder::reverse_rng;
+use routefinder::rng::SggPcg;
+use routefinder::rng_draw::{self, Condition, DrawKind};
+use routefinder::rng_slots::{self, RngSlots};
+use routefinder::rng_trace::{self, first_divergence, RngTrace, TraceRecord};
+use routefinder::scan::{self, ScanConfig, ScanRange};
+use routefinder::simulator::{Simulator, SimulatorBuilder};
+use std::collections::BTreeMap;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::process::ExitCode;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    /// Directory caching compiled Lua files and decoded saves between runs
+    #[arg(long, global = true, value_name = "DIR", default_value = cache::DEFAULT_CACHE_DIR)]
+    cache_dir: PathBuf,
+
+    /// Don't read or write the startup cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Config file to use instead of the discovered routefinder.toml files
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Named profile of the config file to apply (or set ROUTEFINDER_PROFILE)
+    #[arg(long, global = true, value_name = "NAME")]
+    config_profile: Option<String>,
+
+    /// Ignore the config files, ROUTEFINDER_PROFILE and HADES_SCRIPTS_DIR
+    #[arg(long, global = true, conflicts_with_all = ["config", "config_profile"])]
+    no_config: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Arguments setting up the simulation, shared by the commands running scripts
+#[derive(Args)]
+struct SimArgs {
+    /// Save file to use as starting point (defaults to the config's save_file)
+    #[arg(short = 'f', long, value_name = "FILE")]
+    save_file: Option<PathBuf>,
+
+    /// Hades Scripts directory (defaults to HADES_SCRIPTS_DIR or the config's scripts_dir)
+    #[arg(short = 's', long, value_name = "FILE")]
+    scripts_dir: Option<PathBuf>,
+
+    /// Mods directory to import into the scripts, following each mod's modfile.txt
+    #[arg(long, value_name = "DIR")]
+    mods_dir: Option<PathBuf>,
+
+    /// Set Lua variables (format: PATH[:TYPE]=VALUE, e.g. Config.Chamber=5)
+    #[arg(long = "lua-var", value_name = "PATH=VALUE")]
+    lua_vars: Vec<LuaVar>,
+
+    /// Set Lua variables from a TOML or JSON file, before any --lua-var
+    #[arg(long, value_name = "FILE")]
+    lua_vars_file: Option<PathBuf>,
+
+    /// Helper library searched by Import after the script's directory
+    /// (defaults to the config's utils_dir, then this repository's Utils)
+    #[arg(long, value_name = "DIR")]
+    utils_dir: Option<PathBuf>,
+
+    /// Also search DIR for files to Import
+    #[arg(long = "lib-dir", value_name = "DIR")]
+    lib_dirs: Vec<PathBuf>,
+
+    /// Run untrusted scripts without os, io, debug or native modules, and
+    /// with files read only from the scripts, mods and script directories
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Let sandboxed scripts read and write files in DIR
+    #[arg(long = "allow-dir", value_name = "DIR", requires = "sandbox")]
+    allow_dirs: Vec<PathBuf>,
+
+    /// Make reading an undefined global an error outside the game scripts
+    #[arg(long)]
+    strict: bool,
+
+    /// Variables from the config, set before all others
+    #[arg(skip)]
+    config_lua_vars: Vec<LuaVar>,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run a Lua script with game simulation
     Run {
-        script: std::path::PathBuf,
+        /// Script to run (defaults to the config's script)
+        script: Option<PathBuf>,
+
+        #[command(flatten)]
+        sim: SimArgs,
+
+        /// How to print what the script emits: text, json or jsonl. With json
+        /// and jsonl, everything else the script prints goes to stderr
+        #[arg(long, value_name = "FORMAT", default_value = "text")]
+        output: OutputFormat,
+
+        /// Log every RNG call made by the scripts to a jsonl file
+        #[arg(long, value_name = "FILE")]
+        rng_trace: Option<PathBuf>,
+
+        /// Write RNG call counts per calling Lua function to a json file (requires --rng-trace)
+        #[arg(long, value_name = "FILE", requires = "rng_trace")]
+        rng_trace_counts: Option<PathBuf>,
+
+        /// Log every call to an engine function to a jsonl file, and report
+        /// undefined globals the game scripts read on stderr
+        #[arg(long, value_name = "FILE")]
+        engine_calls: Option<PathBuf>,
+
+        /// Define the engine function NAME, recording its calls and returning
+        /// VALUE (parsed like --lua-var); may be repeated
+        #[arg(long = "engine-stub", value_name = "NAME=VALUE")]
+        engine_stubs: Vec<EngineStub>,
+
+        /// Stop the script after this long, e.g. 90s, 10m or 2h
+        #[arg(long, value_name = "DURATION", value_parser = limits::parse_duration)]
+        timeout: Option<Duration>,
+
+        /// Stop the script after about this many Lua VM instructions
+        #[arg(long, value_name = "COUNT")]
+        max_instructions: Option<u64>,
+
+        /// Stop the script once the Lua VM uses more memory than this, e.g. 2G
+        #[arg(long, value_name = "SIZE", value_parser = limits::parse_bytes)]
+        max_memory: Option<usize>,
+
+        /// Sample the Lua stack while the script runs, write the time in
+        /// each stack as folded stacks for flamegraphs, and print the
+        /// slowest functions on stderr
+        #[arg(long, value_name = "FILE")]
+        profile: Option<PathBuf>,
+
+        /// Number of functions in the profile summary
+        #[arg(long, value_name = "N", default_value_t = 20, requires = "profile")]
+        profile_top: usize,
+    },
+    /// Run two configurations and report the first RNG draw where they diverge
+    BisectDesync {
+        /// Script for the first run (defaults to the config's script)
+        script: Option<PathBuf>,
+
+        #[command(flatten)]
+        sim: SimArgs,
+
+        /// Script for the second run (defaults to SCRIPT)
+        #[arg(long, value_name = "FILE")]
+        script_b: Option<PathBuf>,
+
+        /// Save file for the second run (defaults to --save-file)
+        #[arg(long, value_name = "FILE")]
+        save_file_b: Option<PathBuf>,
 
-        /// Save file to use as starting point
-        #[arg(short = 'f', long, value_name = "FILE")]
-        save_file: PathBuf,
+        /// Hades Scripts directory for the second run (defaults to --scripts-dir)
+        #[arg(long, value_name = "FILE")]
+        scripts_dir_b: Option<PathBuf>,
 
-        /// Hades Scripts directory
-        #[arg(short = 's', long, value_name = "FILE")]
-        scripts_dir: PathBuf,
+        /// Mods directory for the second run (defaults to --mods-dir)
+        #[arg(long, value_name = "DIR")]
+        mods_dir_b: Option<PathBuf>,
 
-        /// Set Lua variables (format: variable=value)
-        #[arg(long = "lua-var", value_name = "VAR=VALUE")]
-        lua_vars: Vec<String>,
+        /// Set or override Lua variables for the second run only
+        #[arg(long = "lua-var-b", value_name = "PATH=VALUE")]
+        lua_vars_b: Vec<LuaVar>,
+    },
+    /// Start an interactive Lua prompt with the game and save loaded
+    Repl {
+        #[command(flatten)]
+        sim: SimArgs,
+
+        /// File keeping the inputs of previous sessions
+        #[arg(long, value_name = "FILE", default_value = ".routefinder_history")]
+        history: PathBuf,
+    },
+    /// Run a script for every seed or offset in a range across several Lua VMs
+    Scan {
+        /// Script to run for each value; every line it prints and record it emits is collected
+        #[arg(long, value_name = "FILE")]
+        script: PathBuf,
+
+        /// Seeds to scan, set as the global `Seed` (format: A..B or A..=B)
+        #[arg(long, value_name = "RANGE", required_unless_present = "offsets", conflicts_with = "offsets")]
+        seeds: Option<ScanRange>,
+
+        /// Offsets to scan, set as the global `Offset` (format: A..B or A..=B)
+        #[arg(long, value_name = "RANGE")]
+        offsets: Option<ScanRange>,
+
+        /// Number of Lua VMs to run in parallel (defaults to one per CPU)
+        #[arg(long, default_value_t = 0)]
+        jobs: usize,
+
+        #[command(flatten)]
+        sim: SimArgs,
+    },
+    /// Run route scripts and compare what they print with their expected output
+    Test {
+        /// Tests to run, by name (defaults to every NAME.test in the test directory)
+        names: Vec<String>,
+
+        /// Directory of the expected outputs, NAME.test
+        #[arg(long, value_name = "DIR", default_value = "test")]
+        test_dir: PathBuf,
+
+        /// Directory of the scripts, NAME.lua
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        script_dir: PathBuf,
+
+        /// Replace the expected outputs with what the scripts print
+        #[arg(long)]
+        bless: bool,
+
+        #[command(flatten)]
+        sim: SimArgs,
     },
     /// RNG operations
     Rng {
+        /// Named RNG state to operate on
+        #[arg(long, global = true, default_value = rng_slots::DEFAULT_SLOT)]
+        slot: String,
+
+        /// File holding the RNG states
+        #[arg(long, global = true, value_name = "FILE", default_value = ".rng.json")]
+        state_file: PathBuf,
+
         #[command(subcommand)]
         rng_command: RngCommands,
     },
@@ -52,9 +251,9 @@ enum Commands {
         /// Input file containing data points
         input_file: PathBuf,
         
-        /// Method to use for reverse engineering
-        #[arg(long, default_value = "brute-force")]
-        method: String,
+        /// Method to use for reverse engineering (defaults to the config's search_backend, then brute-force)
+        #[arg(long)]
+        method: Option<String>,
         
     },
 }
@@ -71,218 +270,536 @@ enum RngCommands {
         /// Number of steps to advance (signed integer, will be converted to unsigned)
         steps: i64,
     },
+    /// Step RNG backwards by specified steps
+    Rewind {
+        /// Number of steps to go back
+        steps: u64,
+    },
+    /// Show upcoming draws as the game would render them
+    Peek {
+        /// Number of offsets to show
+        #[arg(long, default_value_t = 10)]
+        count: u64,
+
+        /// How to render each draw: raw, int:MIN:MAX, float, float:MIN:MAX or chance:P
+        #[arg(long = "as", value_name = "DRAW", default_value = "raw")]
+        draw: DrawKind,
+    },
+    /// List the offsets whose draw satisfies a condition
+    Find {
+        /// Condition on the draw, e.g. 'int:1:16<=4' or 'chance:0.25'
+        #[arg(long = "where", value_name = "CONDITION")]
+        condition: Condition,
+
+        /// First offset to check
+        #[arg(long, default_value_t = 0)]
+        start: u64,
+
+        /// Number of offsets to check
+        #[arg(long, default_value_t = 1000)]
+        limit: u64,
+    },
+    /// Revert the last set-seed or advance on the slot
+    Undo,
+    /// Show the slot's state and history, and the other slots
+    Show,
 }
 
 type Result<T, E = error::Error> = core::result::Result<T, E>;
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Exit status when the route script raises an error, or a `test` fails.
+const EXIT_SCRIPT_FAILED: u8 = 1;
 
-    match cli.command {
-        Commands::Run { script, save_file, scripts_dir, lua_vars } => {
-            run_script(script, save_file, scripts_dir, lua_vars)
-        }
-        Commands::Rng { rng_command } => {
-            handle_rng_command(rng_command)
-        }
-        Commands::ReverseRng { input_file, method } => {
-            handle_reverse_rng_command(input_file, method)
+/// Exit status when the route script is stopped by `--timeout`,
+/// `--max-instructions` or `--max-memory`.
+const EXIT_LIMIT_EXCEEDED: u8 = 3;
+
+/// Exit status when the simulation can't be set up, e.g. for a missing save
+/// file, and for invalid arguments.
+const EXIT_SETUP_FAILED: u8 = 2;
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(EXIT_SETUP_FAILED)
         }
     }
 }
 
-fn run_script(route_finder_script: PathBuf, save_file_path: PathBuf, hades_scripts_dir: PathBuf, lua_vars: Vec<String>) -> Result<()> {
-    let lua = unsafe { Lua::unsafe_new_with(mlua::StdLib::ALL, LuaOptions::new()) };
-
-    let shared_rng = Rc::new(RefCell::new(SggPcg::new(0)));
-
-    // Load save file
-    let save_file = read_file(save_file_path)?;
-    let lua_state_lz4 = save::read(&mut save_file.as_slice(), "save".to_string())?.lua_state_lz4;
-    let lua_state = lz4::block::decompress(
-        &lua_state_lz4.as_slice(),
-        Some(save::HadesSaveV16::UNCOMPRESSED_SIZE),
-    )?;
-
-    lua.scope(|scope: &mlua::Scope| -> Result<(), mlua::Error> {
-        let getmetatable = scope.create_function(|_, table: Table| {
-            Ok(table.get_metatable())
-        })?;
-        lua.globals().set("getmetatable", getmetatable)?;
-
-        // Engine callbacks etc.
-        let _ = load_lua_file(&lua, &"Engine.lua")?;
-
-        // Hooks into the engine for RNG
-        let randomseed = scope.create_function(|_, (o_seed, id): (Option<i32>, i32)| {
-            let seed = match o_seed {
-                Some(s) => s,
-                None => 0,
+fn run(cli: Cli) -> Result<ExitCode> {
+    let cache_dir = if cli.no_cache { None } else { Some(cli.cache_dir) };
+    let config_file = cli.config;
+    let config_profile = cli.config_profile;
+    let no_config = cli.no_config;
+    let config = || -> Result<Profile> {
+        let config = match &config_file {
+            _ if no_config => Config::default(),
+            Some(path) => Config::from_file(path, config_profile.as_deref())?,
+            None => Config::discover(config_profile.as_deref())?,
+        };
+        Ok(config.profile)
+    };
+
+    match cli.command {
+        Commands::Run {
+            script,
+            sim,
+            output,
+            rng_trace,
+            rng_trace_counts,
+            engine_calls,
+            engine_stubs,
+            timeout,
+            max_instructions,
+            max_memory,
+            profile,
+            profile_top,
+        } => {
+            let config = config()?;
+            let script = script_or_default(script, &config)?;
+            let sim = sim.with_config(&config)?;
+            let mut builder = sim.allow_script(sim.builder(&cache_dir)?, &script);
+            if let Some(path) = rng_trace {
+                builder = builder.rng_trace(RngTrace::create(path, rng_trace_counts)?);
+            }
+            if engine_calls.is_some() {
+                builder = builder.record_engine_calls();
+            }
+            for stub in engine_stubs {
+                builder = builder.engine_stub(stub);
+            }
+            if let Some(timeout) = timeout {
+                builder = builder.timeout(timeout);
+            }
+            if let Some(count) = max_instructions {
+                builder = builder.max_instructions(count);
+            }
+            if let Some(bytes) = max_memory {
+                builder = builder.max_memory(bytes);
+            }
+            if profile.is_some() {
+                builder = builder.profile();
+            }
+            let run = run_script(&script, builder, output)?;
+            if let Some(path) = engine_calls {
+                write_engine_calls(&path, &run)?;
+            }
+            if let (Some(path), Some(profile)) = (profile, &run.profile) {
+                profile.write_folded(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+                eprint!("{}", profile.summary(profile_top));
+            }
+            if let Some(status) = run.failed {
+                return Ok(ExitCode::from(status));
+            }
+            Ok(())
+        }
+        Commands::BisectDesync { script, sim, script_b, save_file_b, scripts_dir_b, mods_dir_b, lua_vars_b } => {
+            let config = config()?;
+            let script = script_or_default(script, &config)?;
+            let sim = sim.with_config(&config)?;
+            let builder_a = || -> Result<SimulatorBuilder> { Ok(sim.allow_script(sim.builder(&cache_dir)?, &script)) };
+            // later assignments win, so B's variables override the shared ones
+            let sim_b = SimArgs {
+                save_file: save_file_b.or_else(|| sim.save_file.clone()),
+                scripts_dir: scripts_dir_b.or_else(|| sim.scripts_dir.clone()),
+                mods_dir: mods_dir_b.or_else(|| sim.mods_dir.clone()),
+                lua_vars: sim.lua_vars.iter().cloned().chain(lua_vars_b).collect(),
+                lua_vars_file: sim.lua_vars_file.clone(),
+                utils_dir: sim.utils_dir.clone(),
+                lib_dirs: sim.lib_dirs.clone(),
+                sandbox: sim.sandbox,
+                strict: sim.strict,
+                allow_dirs: sim.allow_dirs.clone(),
+                config_lua_vars: sim.config_lua_vars.clone(),
             };
-            let mut rng = shared_rng.borrow_mut();
-            *rng = SggPcg::new(seed as u64);
-            Ok(id)
-        })?;
-        lua.globals().set("randomseed", randomseed)?;
-
-        let randomint = scope.create_function(|_, (min, max, _id): (i32, i32, Value)| {
-            let mut rng = shared_rng.borrow_mut();
-            Ok(rand_int(&mut *rng, min, max))
-        })?;
-        lua.globals().set("randomint", randomint)?;
-
-        let random = scope.create_function(|_, _args: Variadic<Value>| {
-            let mut rng = shared_rng.borrow_mut();
-            Ok(rand_double(&mut *rng))
-        })?;
-        lua.globals().set("random", random)?;
-
-        let randomgaussian = scope.create_function(|_, _args: Variadic<Value>| {
-            Ok(0.0) // only affects enemy ratios in encounters, but not number of waves or types
-        })?;
-        lua.globals().set("randomgaussian", randomgaussian)?;
-
-        let luabins_read = scope.create_function(|lua, filename: String| -> Result<Value, mlua::Error> {
-            let file_path = std::path::Path::new(&filename);
-            let file_data = std::fs::read(file_path)
-                .map_err(|e| mlua::Error::runtime(format!("Failed to read file '{}': {}", filename, e)))?;
-            
-            let mut data_slice = file_data.as_slice();
-            match luabins::load(lua, &mut data_slice, format!("luabins_read {}", filename)) {
-                Ok(values) => {
-                    if values.len() == 1 {
-                        Ok(values.into_iter().next().unwrap())
-                    } else {
-                        Ok(Value::Table(lua.create_table_from(values.into_iter().enumerate())?))
-                    }
-                },
-                Err(e) => Err(mlua::Error::runtime(format!("Failed to parse luabins file '{}': {}", filename, e)))
+            let script_b = script_b.unwrap_or_else(|| script.clone());
+            let builder_b = || -> Result<SimulatorBuilder> { Ok(sim_b.allow_script(sim_b.builder(&cache_dir)?, &script_b)) };
+
+            println!("=== run A ===");
+            let trace_a = run_script(&script, builder_a()?.rng_trace(RngTrace::draws()), OutputFormat::Text)?.trace.unwrap();
+            println!("=== run B ===");
+            let trace_b = run_script(&script_b, builder_b()?.rng_trace(RngTrace::draws()), OutputFormat::Text)?.trace.unwrap();
+
+            let (draws_a, draws_b) = (trace_a.draw_list(), trace_b.draw_list());
+            println!();
+            let index = match first_divergence(draws_a, draws_b) {
+                Some(index) => index,
+                None => {
+                    println!("No divergence in {} draws", draws_a.len());
+                    return Ok(ExitCode::SUCCESS);
+                }
+            };
+            println!("First divergence at draw {} (A made {} draws, B made {})", index, draws_a.len(), draws_b.len());
+            // only the draws around the divergence are worth their stacks, so rerun up to it
+            let from = index.saturating_sub(BISECT_CONTEXT);
+            report_divergence("A", index - from, &capture_draws(&script, builder_a()?, from, index)?);
+            report_divergence("B", index - from, &capture_draws(&script_b, builder_b()?, from, index)?);
+            Ok(())
+        }
+        Commands::Repl { sim, history } => {
+            let sim = sim.with_config(&config()?)?;
+            let lua_vars = sim.all_lua_vars()?;
+            let build = || sim.builder_with(&cache_dir, lua_vars.iter().cloned()).build();
+            let stdin = std::io::stdin();
+            repl::run(build, stdin.lock(), std::io::stdout(), Some(&history))
+        }
+        Commands::Scan { script, seeds, offsets, jobs, sim } => {
+            let sim = sim.with_config(&config()?)?;
+            let (variable, range) = match (seeds, offsets) {
+                (Some(seeds), _) => ("Seed", seeds),
+                (None, Some(offsets)) => ("Offset", offsets),
+                (None, None) => unreachable!("clap requires --seeds or --offsets"),
+            };
+            let lua_vars = sim.all_lua_vars()?;
+            let rows = scan::scan(&ScanConfig {
+                script: script.clone(),
+                variable: variable.to_string(),
+                range,
+                jobs,
+                builder: || sim.allow_script(sim.builder_with(&cache_dir, lua_vars.iter().cloned()), &script),
+            })?;
+            for row in rows {
+                println!("{}\t{}", row.value, row.line);
             }
-        })?;
-        lua.globals().set("LuabinsRead", luabins_read)?;
-
-        let luabins_write = scope.create_function(|_, (filename, table): (String, Value)| -> Result<(), mlua::Error> {
-            let file_path = std::path::Path::new(&filename);
-            let mut data = Vec::new();
-            let values = vec![table];
-            match luabins::save(&mut data, values) {
-                Ok(()) => {
-                    std::fs::write(file_path, data)
-                        .map_err(|e| mlua::Error::runtime(format!("Failed to write file '{}': {}", filename, e)))
-                },
-                Err(e) => Err(mlua::Error::runtime(format!("Failed to serialize luabins data for '{}': {}", filename, e)))
+            Ok(())
+        }
+        Commands::Test { names, test_dir, script_dir, bless, sim } => {
+            let sim = sim.with_config(&config()?)?;
+            let tests = golden::find_tests(&test_dir, &script_dir, &names)?;
+            let mut builder = sim.builder(&cache_dir)?;
+            if sim.sandbox {
+                builder = builder.allow_read_dir(&script_dir);
             }
-        })?;
-        lua.globals().set("LuabinsWrite", luabins_write)?;
-
-        // Load lua files
-        load_lua_file(&lua, &hades_scripts_dir.join("Main.lua"))?;
-        load_lua_file(&lua, &hades_scripts_dir.join("RoomManager.lua"))?;
-
-        let save_data = luabins::load(&lua, &mut lua_state.as_slice(), "luabins".to_string()).unwrap();
-
-        lua.globals().set("RouteFinderSaveFileData", save_data)?;
-
-        // put save file data into globals
-        lua.load(
-            r#"
-            for _,savedValues in pairs(RouteFinderSaveFileData) do
-                for key, value in pairs(savedValues) do
-                if not SaveIgnores[key] then
-                    _G[key] = value
-                end
-                end
-            end
-            "#,
-        )
-        .exec()?;
-
-        // Set custom Lua variables from command line
-        for lua_var in &lua_vars {
-            if let Some((var_name, var_value)) = lua_var.split_once('=') {
-                // Try to parse as different types
-                if let Ok(int_val) = var_value.parse::<i64>() {
-                    lua.globals().set(var_name, int_val)?;
-                } else if let Ok(float_val) = var_value.parse::<f64>() {
-                    lua.globals().set(var_name, float_val)?;
-                } else if var_value.eq_ignore_ascii_case("true") {
-                    lua.globals().set(var_name, true)?;
-                } else if var_value.eq_ignore_ascii_case("false") {
-                    lua.globals().set(var_name, false)?;
-                } else {
-                    // Treat as string
-                    lua.globals().set(var_name, var_value)?;
+            let outcomes = golden::run_tests(&tests, builder, bless, |test, outcome| match outcome {
+                Outcome::Passed => println!("test {} ... ok", test.name),
+                Outcome::Blessed => println!("test {} ... blessed", test.name),
+                Outcome::Failed { diff } => {
+                    println!("test {} ... FAILED", test.name);
+                    println!("--- {}\n+++ output of {}\n{}", test.expected.display(), test.script.display(), diff);
                 }
-            } else {
-                eprintln!("Warning: Invalid lua-var format '{}'. Expected format: variable=value", lua_var);
+                Outcome::Error { message } => {
+                    println!("test {} ... ERROR", test.name);
+                    println!("{}\n", message.trim_end());
+                }
+            })?;
+            let passed = outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Passed | Outcome::Blessed)).count();
+            let failed = outcomes.len() - passed;
+            println!();
+            println!("test result: {}. {} passed; {} failed", if failed == 0 { "ok" } else { "FAILED" }, passed, failed);
+            if failed > 0 {
+                return Ok(ExitCode::from(EXIT_SCRIPT_FAILED));
             }
+            Ok(())
+        }
+        Commands::Rng { slot, state_file, rng_command } => {
+            handle_rng_command(rng_command, &slot, &state_file)
+        }
+        Commands::ReverseRng { input_file, method } => {
+            let method = match method {
+                Some(method) => method,
+                None => config()?.search_backend.unwrap_or_else(|| "brute-force".to_string()),
+            };
+            handle_reverse_rng_command(input_file, method)
         }
+    }
+    .map(|()| ExitCode::SUCCESS)
+}
+
+fn script_or_default(script: Option<PathBuf>, config: &Profile) -> Result<PathBuf> {
+    script
+        .or_else(|| config.script.clone())
+        .ok_or_else(|| error::Error::from("No script given, and the config sets no default script".to_string()))
+}
 
-        // load and run script
-        match load_lua_file(&lua, &route_finder_script) {
-            Ok(()) => {}
-            Err(err) => {
-                println!("Error: {}", err.to_string());
+impl SimArgs {
+    /// Fill in what wasn't given on the command line from `config`. The
+    /// save file and scripts directory must come from one of them.
+    fn with_config(mut self, config: &Profile) -> Result<SimArgs> {
+        self.save_file = self.save_file.or_else(|| config.save_file.clone());
+        self.scripts_dir = self.scripts_dir.or_else(|| config.scripts_dir.clone());
+        self.mods_dir = self.mods_dir.or_else(|| config.mods_dir.clone());
+        self.utils_dir = self.utils_dir.or_else(|| config.utils_dir.clone());
+        self.lib_dirs.extend(config.lib_dirs.iter().cloned());
+        self.config_lua_vars = config.lua_vars.clone();
+        if self.save_file.is_none() {
+            return Err(error::Error::from("No save file: pass --save-file or set save_file in routefinder.toml".to_string()));
+        }
+        if self.scripts_dir.is_none() {
+            return Err(error::Error::from(
+                "No scripts directory: pass --scripts-dir, set HADES_SCRIPTS_DIR or set scripts_dir in routefinder.toml"
+                    .to_string(),
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Variables from the config, then the vars file, then the ones given
+    /// on the command line, so that later ones win.
+    fn all_lua_vars(&self) -> Result<Vec<LuaVar>> {
+        let mut vars = self.config_lua_vars.clone();
+        if let Some(path) = &self.lua_vars_file {
+            vars.extend(lua_vars::load_file(path)?);
+        }
+        vars.extend(self.lua_vars.iter().cloned());
+        Ok(vars)
+    }
+
+    fn builder(&self, cache_dir: &Option<PathBuf>) -> Result<SimulatorBuilder> {
+        Ok(self.builder_with(cache_dir, self.all_lua_vars()?))
+    }
+
+    fn builder_with<I: IntoIterator<Item = LuaVar>>(&self, cache_dir: &Option<PathBuf>, lua_vars: I) -> SimulatorBuilder {
+        let mut builder = Simulator::builder().lua_vars(lua_vars);
+        if let Some(save_file) = &self.save_file {
+            builder = builder.save_file(save_file);
+        }
+        if let Some(scripts_dir) = &self.scripts_dir {
+            builder = builder.scripts_dir(scripts_dir);
+        }
+        if let Some(mods_dir) = &self.mods_dir {
+            builder = builder.mods_dir(mods_dir);
+        }
+        if let Some(cache_dir) = cache_dir {
+            builder = builder.cache_dir(cache_dir);
+        }
+        if let Some(utils_dir) = &self.utils_dir {
+            builder = builder.utils_dir(utils_dir);
+        }
+        for dir in &self.lib_dirs {
+            builder = builder.lib_dir(dir);
+        }
+        if self.strict {
+            builder = builder.strict();
+        }
+        if self.sandbox {
+            builder = builder.sandbox();
+            for dir in &self.allow_dirs {
+                builder = builder.allow_dir(dir);
             }
-        };
-        Ok(())
-    })?;
+        }
+        builder
+    }
 
+    /// Let a sandboxed `script` read the files next to it, like the
+    /// helpers it imports.
+    fn allow_script(&self, builder: SimulatorBuilder, script: &Path) -> SimulatorBuilder {
+        match script.parent() {
+            Some(dir) if self.sandbox => builder.allow_read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }),
+            _ => builder,
+        }
+    }
+}
+
+/// The end of a `run_script`.
+struct ScriptRun {
+    trace: Option<RngTrace>,
+    engine_calls: Vec<EngineCall>,
+    missing_engine_globals: BTreeMap<String, MissingGlobal>,
+    profile: Option<profiler::Profile>,
+    /// Exit status for the error the script raised, already reported on
+    /// stderr.
+    failed: Option<u8>,
+}
+
+fn run_script(route_finder_script: &Path, mut builder: SimulatorBuilder, output: OutputFormat) -> Result<ScriptRun> {
+    match output {
+        OutputFormat::Text => {
+            builder = builder.on_emit(|record: EmitRecord| {
+                let data = serde_json::to_string_pretty(&record.data).unwrap_or_default();
+                println!("{} {}", record.name, data);
+            });
+        }
+        OutputFormat::Jsonl => {
+            builder = builder.output(std::io::stderr()).on_emit(|record: EmitRecord| {
+                println!("{}", serde_json::to_string(&record).unwrap_or_default());
+            });
+        }
+        OutputFormat::Json => builder = builder.output(std::io::stderr()),
+    }
+    let sim = builder.build()?;
+
+    // load and run script
+    let result = sim.run_file(route_finder_script);
+    if let Err(err) = &result {
+        eprintln!("Error: {}", err);
+    }
+    if output == OutputFormat::Json {
+        let records = serde_json::to_string_pretty(&sim.take_emitted())
+            .map_err(|e| error::Error::from(format!("Failed to serialize emitted records: {}", e)))?;
+        println!("{}", records);
+    }
+    Ok(ScriptRun {
+        engine_calls: sim.take_engine_calls(),
+        missing_engine_globals: sim.missing_engine_globals(),
+        profile: sim.take_profile(),
+        trace: sim.finish()?,
+        failed: match result {
+            Ok(()) => None,
+            Err(error::Error::Limit { .. }) => Some(EXIT_LIMIT_EXCEEDED),
+            Err(_) => Some(EXIT_SCRIPT_FAILED),
+        },
+    })
+}
+
+fn write_engine_calls(path: &Path, run: &ScriptRun) -> Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for call in &run.engine_calls {
+        let line = serde_json::to_string(call)
+            .map_err(|e| error::Error::from(format!("Failed to serialize engine call: {}", e)))?;
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()?;
+
+    if !run.missing_engine_globals.is_empty() {
+        eprintln!("Undefined globals read by the game scripts:");
+        for (name, missing) in &run.missing_engine_globals {
+            let location = missing.first_location.as_deref().unwrap_or("?");
+            eprintln!("  {} ({} reads, first at {})", name, missing.reads, location);
+        }
+    }
     Ok(())
 }
 
-fn handle_rng_command(rng_command: RngCommands) -> Result<()> {
-    const STATE_FILE: &str = ".rng.json";
+/// Draws shown before the first divergence, for context.
+const BISECT_CONTEXT: usize = 3;
+
+/// Rerun `script` quietly up to draw `to`, keeping the full records of the
+/// draws from `from` on.
+fn capture_draws(script: &Path, builder: SimulatorBuilder, from: usize, to: usize) -> Result<Vec<TraceRecord>> {
+    let sim = builder
+        .output(std::io::sink())
+        .on_emit(|_| {})
+        .rng_trace(RngTrace::window(from, to, rng_trace::BISECT_STACK_DEPTH))
+        .build()?;
+    // the trace stops the script once it has the window
+    let _ = sim.run_file(script);
+    Ok(sim.finish()?.map(|trace| trace.records().to_vec()).unwrap_or_default())
+}
+
+/// Print one side's draws leading up to the divergence, which is at
+/// `records[index]`, with its stack.
+fn report_divergence(name: &str, index: usize, records: &[TraceRecord]) {
+    for record in &records[..index.min(records.len())] {
+        println!("{}: {}", name, describe_draw(record));
+    }
+    match records.get(index) {
+        Some(record) => {
+            println!("{}: {}  <- diverges", name, describe_draw(record));
+            for frame in &record.stack {
+                println!("    {}", frame);
+            }
+        }
+        None => println!("{}: no further draws", name),
+    }
+}
+
+fn describe_draw(record: &TraceRecord) -> String {
+    let args: Vec<String> = record.args.iter().map(|arg| arg.to_string()).collect();
+    format!(
+        "{}({}) on stream {} at offset {} -> {}",
+        record.call,
+        args.join(", "),
+        record.stream,
+        record.offset,
+        record.result
+    )
+}
+
+fn handle_rng_command(rng_command: RngCommands, slot: &str, state_file: &Path) -> Result<()> {
+    let mut slots = RngSlots::load(state_file)?;
 
     match rng_command {
         RngCommands::SetSeed { seed } => {
             let rng = SggPcg::new(seed as u64);
             println!("RNG seed set to: {}", seed);
+            print_preview(&rng);
 
-            let mut preview_rng: SggPcg = rng.clone();
-            for i in 0..3 {
-                let value = preview_rng.next_u32();
-                println!("  {}: {}", i, value);
-            }
-
-            // Save state to file
-            if let Err(e) = rng.save_to_file(STATE_FILE) {
-                eprintln!("Warning: Failed to save RNG state: {}", e);
-            }
+            slots.update(slot, format!("set-seed {}", seed), rng);
+            save_rng_slots(&slots, state_file);
         }
         RngCommands::Advance { steps } => {
-            // Load existing state from file, or create new if file doesn't exist
-            let mut rng = match SggPcg::load_from_file(STATE_FILE) {
-                Ok(loaded_rng) => {
-                    println!("Loaded RNG state from {}", STATE_FILE);
-                    loaded_rng
-                }
-                Err(_) => {
-                    println!("No existing RNG state found, creating new with seed 0");
-                    SggPcg::new(0)
-                }
-            };
+            let mut rng = load_rng_state(&slots, slot);
 
             println!("Current RNG state before advancing:");
-            let mut preview_rng: SggPcg = rng.clone();
-            for i in 0..3 {
-                let value = preview_rng.next_u32();
-                println!("  {}: {}", i, value);
-            }
+            print_preview(&rng);
 
             rng.advance(steps as u64);
 
             println!("RNG advanced by {} steps", steps);
             println!("Values after advance:");
-            preview_rng = rng.clone();
-            for i in 0..3 {
-                let value = preview_rng.next_u32();
-                println!("  {}: {}", i, value);
+            print_preview(&rng);
+
+            slots.update(slot, format!("advance {}", steps), rng);
+            save_rng_slots(&slots, state_file);
+        }
+        RngCommands::Rewind { steps } => {
+            let mut rng = load_rng_state(&slots, slot);
+
+            println!("Values preceding the current RNG state:");
+            let mut preceding = rng.clone();
+            preceding.retreat(steps.min(3));
+            for (i, value) in preceding.outputs(steps.min(3)).rev().enumerate() {
+                println!("  -{}: {}", i + 1, value);
             }
 
-            // Save state to file
-            if let Err(e) = rng.save_to_file(STATE_FILE) {
-                eprintln!("Warning: Failed to save RNG state: {}", e);
+            rng.retreat(steps);
+
+            println!("RNG rewound by {} steps", steps);
+            println!("Values after rewind:");
+            print_preview(&rng);
+
+            slots.update(slot, format!("rewind {}", steps), rng);
+            save_rng_slots(&slots, state_file);
+        }
+        RngCommands::Peek { count, draw } => {
+            let mut rng = load_rng_state(&slots, slot);
+            for offset in 0..count {
+                let (raw, value) = draw.peek(&rng);
+                println!("  {}: {} (raw {})", offset, value, raw);
+                rng.next_u32();
+            }
+        }
+        RngCommands::Find { condition, start, limit } => {
+            let rng = load_rng_state(&slots, slot);
+            let found = rng_draw::find(&rng, &condition, start, limit);
+            for (offset, raw, value) in &found {
+                println!("  {}: {} (raw {})", offset, value, raw);
+            }
+            println!("{} of {} offsets match", found.len(), limit);
+        }
+        RngCommands::Undo => {
+            match slots.undo(slot) {
+                Some(entry) => {
+                    println!("Undid '{}' on slot {}", entry.operation, slot);
+                    match slots.get(slot) {
+                        Some(rng) => print_preview(rng),
+                        None => println!("Slot {} is now empty", slot),
+                    }
+                    save_rng_slots(&slots, state_file);
+                }
+                None => println!("Nothing to undo on slot {}", slot),
+            }
+        }
+        RngCommands::Show => {
+            match slots.slots.get(slot) {
+                Some(entry) => {
+                    println!("Slot {}:", slot);
+                    match &entry.rng {
+                        Some(rng) => print_preview(rng),
+                        None => println!("(empty)"),
+                    }
+                    println!("History:");
+                    for (i, history_entry) in entry.history.iter().enumerate() {
+                        println!("  {}: {} (at {})", i, history_entry.operation, history_entry.time);
+                    }
+                }
+                None => println!("Slot {} is empty", slot),
+            }
+            let others: Vec<&String> = slots.slots.keys().filter(|name| *name != slot).collect();
+            if !others.is_empty() {
+                println!("Other slots: {}", others.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", "));
             }
         }
     }
@@ -290,6 +807,35 @@ fn handle_rng_command(rng_command: RngCommands) -> Result<()> {
     Ok(())
 }
 
+fn print_preview(rng: &SggPcg) {
+    println!("  uses: {}", rng.uses());
+    let mut preview_rng: SggPcg = rng.clone();
+    for i in 0..3 {
+        let value = preview_rng.next_u32();
+        println!("  {}: {}", i, value);
+    }
+}
+
+/// Load the slot's state, or create new if the slot doesn't exist
+fn load_rng_state(slots: &RngSlots, slot: &str) -> SggPcg {
+    match slots.get(slot) {
+        Some(rng) => {
+            println!("Loaded RNG state from slot {}", slot);
+            rng.clone()
+        }
+        None => {
+            println!("No existing RNG state found, creating new with seed 0");
+            SggPcg::new(0)
+        }
+    }
+}
+
+fn save_rng_slots(slots: &RngSlots, state_file: &Path) {
+    if let Err(e) = slots.save(state_file) {
+        eprintln!("Warning: Failed to save RNG state: {}", e);
+    }
+}
+
 
 fn handle_reverse_rng_command(input_file: PathBuf, method: String) -> Result<()> {
     use reverse_rng::data_point;
@@ -309,11 +855,11 @@ fn handle_reverse_rng_command(input_file: PathBuf, method: String) -> Result<()>
             println!("Using brute force method...");
             #[cfg(feature = "simd")]
             {
-                reverse_rng::simd_search::find_original_state_simd(&data_points)?
+                reverse_rng::simd_search::find_original_state_simd::<SggPcg>(&data_points)?
             }
             #[cfg(not(feature = "simd"))]
             {
-                reverse_rng::search::find_original_state(&data_points)?
+                reverse_rng::search::find_original_state::<SggPcg>(&data_points)?
             }
         }
         _ => {
@@ -335,58 +881,3 @@ fn handle_reverse_rng_command(input_file: PathBuf, method: String) -> Result<()>
     
     Ok(())
 }
-
-const BYTE_ORDER_MARK: &[u8] = "\u{feff}".as_bytes();
-fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
-    let file = fs::read(path)?;
-    if file.starts_with(BYTE_ORDER_MARK) {
-        Ok(file[3..].to_vec())
-    } else {
-        Ok(file.to_vec())
-    }
-}
-
-fn load_lua_file<'lua, P: AsRef<Path>>(lua: &Lua, path: &P) -> Result<(), mlua::Error> {
-    let abs_path = path.as_ref().canonicalize()?;
-    let parent_path = abs_path.parent().ok_or("No parent path".to_string()).unwrap();
-
-    lua.scope(|scope| {
-        let import = scope.create_function(|inner_lua, import_str: String| {
-            let import_n1 = import_str.clone();
-            let import_n2 = import_str.clone();
-            let import_file = read_file(parent_path.join(import_n1))?;
-            inner_lua.load(&import_file).set_name(&import_n2).exec()
-        })?;
-
-        let file = read_file(path)?;
-        lua.globals().set("Import", import)?;
-        lua.load(&file).exec()?;
-        Ok(())
-    })
-}
-
-fn rand_int(rng: &mut SggPcg, min: i32, max: i32) -> i32 {
-    // println!("rand_int min {} max {}", min, max);
-    if max > min {
-        let bound = (max as u32).wrapping_sub(min as u32).wrapping_add(1);
-        min.wrapping_add(bounded(rng, bound) as i32)
-    } else {
-        rng.next_u32(); // advance and ignore result (to keep in sync)
-        min
-    }
-}
-
-fn bounded(rng: &mut SggPcg, bound: u32) -> u32 {
-    let threshold = (u32::MAX - bound + 1) % bound;
-
-    loop {
-        let r = rng.next_u32();
-        if r >= threshold {
-            return r % bound;
-        }
-    }
-}
-
-fn rand_double(rng: &mut SggPcg) -> f64 {
-    ldexp(rng.next_u32() as f64, -32)
-}
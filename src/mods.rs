@@ -0,0 +1,191 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the file that tells the mod importer what a mod changes.
+pub const MODFILE: &str = "modfile.txt";
+
+/// Priority of mods that don't set `Load Priority`.
+pub const DEFAULT_PRIORITY: i64 = 100;
+
+/// Script that `Import` directives add to until a `To` says otherwise.
+const DEFAULT_TARGET: &str = "Scripts/RoomManager.lua";
+
+/// A mod folder, read from its `modfile.txt`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mod {
+    /// Folder of the modfile, relative to the mods directory.
+    pub name: String,
+    pub priority: i64,
+    pub imports: Vec<ModImport>,
+}
+
+/// A Lua file the mod importer would import into a game script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModImport {
+    /// Game script imported into, relative to the `Scripts` directory.
+    pub target: String,
+    /// Imported before the script's own code rather than after it.
+    pub top: bool,
+    pub path: PathBuf,
+}
+
+/// Find every `modfile.txt` under `dir` and return the mods in load order:
+/// by `Load Priority`, lowest first, then by name.
+pub fn load_mods<P: AsRef<Path>>(dir: P) -> Result<Vec<Mod>, Error> {
+    let dir = dir.as_ref();
+    let mut modfiles = Vec::new();
+    find_modfiles(dir, &mut modfiles)?;
+
+    let mut mods = Vec::new();
+    for modfile in modfiles {
+        let mod_dir = modfile.parent().unwrap_or(dir);
+        let name = mod_dir.strip_prefix(dir).unwrap_or(mod_dir).to_string_lossy().replace('\\', "/");
+        let text = std::fs::read_to_string(&modfile)?;
+        mods.push(parse_modfile(&name, mod_dir, &text)?);
+    }
+    mods.sort_by(|a, b| (a.priority, &a.name).cmp(&(b.priority, &b.name)));
+    Ok(mods)
+}
+
+fn find_modfiles(dir: &Path, modfiles: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_modfiles(&path, modfiles)?;
+        } else if path.file_name().is_some_and(|name| name.eq_ignore_ascii_case(MODFILE)) {
+            modfiles.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Read the Lua directives of a modfile: `To`, `Load Priority`, `Import` and
+/// `Top Import`. Directives editing data files (`XML`, `SJSON`, ...) have
+/// no effect on the simulation and are skipped.
+pub fn parse_modfile(name: &str, mod_dir: &Path, text: &str) -> Result<Mod, Error> {
+    let invalid = |line: usize, e: &str| Error::from(format!("Invalid {}/{} line {}: {}", name, MODFILE, line + 1, e));
+    let mut priority = DEFAULT_PRIORITY;
+    let mut targets = vec![DEFAULT_TARGET.to_string()];
+    let mut imports = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split("::").next().unwrap_or("");
+        let tokens = tokenize(line).map_err(|e| invalid(number, e))?;
+        let words: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            [] => {}
+            ["Load", "Priority", value] => {
+                priority = value.parse().map_err(|_| invalid(number, "expected a number after Load Priority"))?;
+            }
+            ["To"] => targets = vec![DEFAULT_TARGET.to_string()],
+            ["To", files @ ..] => targets = files.iter().map(|file| file.to_string()).collect(),
+            ["Import", files @ ..] | ["Top", "Import", files @ ..] => {
+                let top = words[0] == "Top";
+                for target in &targets {
+                    for file in files {
+                        imports.push(ModImport { target: normalize_target(target), top, path: mod_dir.join(file) });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Mod { name: name.to_string(), priority, imports })
+}
+
+/// Split a line on whitespace, keeping double-quoted strings whole.
+fn tokenize(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let token: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    if line.matches('"').count() % 2 == 1 {
+        return Err("unterminated string");
+    }
+    Ok(tokens)
+}
+
+/// The name a game script is imported by: relative to `Scripts`, with `/`
+/// separators and ignoring case like the game's file system does.
+pub fn normalize_target(path: &str) -> String {
+    let path = path.replace('\\', "/").to_ascii_lowercase();
+    match path.strip_prefix("scripts/") {
+        Some(path) => path.to_string(),
+        None => path,
+    }
+}
+
+/// Mod files to run around each game script, in load order.
+#[derive(Debug, Default)]
+pub struct ModImports {
+    top: HashMap<String, Vec<PathBuf>>,
+    bottom: HashMap<String, Vec<PathBuf>>,
+}
+
+impl ModImports {
+    pub fn new(mods: &[Mod]) -> Self {
+        let mut imports = ModImports::default();
+        for import in mods.iter().flat_map(|m| &m.imports) {
+            let by_target = if import.top { &mut imports.top } else { &mut imports.bottom };
+            by_target.entry(import.target.clone()).or_default().push(import.path.clone());
+        }
+        imports
+    }
+
+    /// Files imported at the top of the game script `name`.
+    pub fn top(&self, name: &str) -> &[PathBuf] {
+        self.top.get(&normalize_target(name)).map_or(&[], Vec::as_slice)
+    }
+
+    /// Files imported at the end of the game script `name`.
+    pub fn bottom(&self, name: &str) -> &[PathBuf] {
+        self.bottom.get(&normalize_target(name)).map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_modfile() {
+        let text = "\
+:: comment
+Load Priority 5
+Import \"Core.lua\"
+To \"Scripts/Main.lua\"
+Top Import \"Early.lua\" \"Earlier.lua\"
+SJSON \"Game/Text/en/HelpText.en.sjson\"
+";
+        let parsed = parse_modfile("Demo", Path::new("Mods/Demo"), text).unwrap();
+        assert_eq!(parsed.priority, 5);
+        let imports = ModImports::new(&[parsed]);
+        assert_eq!(imports.bottom("RoomManager.lua"), &[Path::new("Mods/Demo").join("Core.lua")]);
+        assert_eq!(
+            imports.top("main.lua"),
+            &[Path::new("Mods/Demo").join("Early.lua"), Path::new("Mods/Demo").join("Earlier.lua")]
+        );
+        assert!(imports.top("RoomManager.lua").is_empty());
+
+        assert!(parse_modfile("Demo", Path::new("."), "Load Priority high").is_err());
+        assert!(parse_modfile("Demo", Path::new("."), "Import \"Core.lua").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::rng_trace::lua_frames;
+use mlua::Lua;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Instant;
+
+/// Samples the Lua stack from the instruction hook, weighting each sample
+/// by the time since the previous one.
+#[derive(Debug)]
+pub(crate) struct Profiler {
+    last_sample: Cell<Instant>,
+    profile: RefCell<Profile>,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Profiler {
+        Profiler { last_sample: Cell::new(Instant::now()), profile: RefCell::default() }
+    }
+
+    pub(crate) fn sample(&self, lua: &Lua) {
+        let now = Instant::now();
+        let micros = now.duration_since(self.last_sample.replace(now)).as_micros() as u64;
+        // level 0 is the function the hook interrupted
+        let mut stack: Vec<String> = lua_frames(lua, 0).map(|debug| frame_name(&debug)).collect();
+        if stack.is_empty() {
+            return;
+        }
+        stack.reverse();
+        *self.profile.borrow_mut().stacks.entry(stack).or_default() += micros;
+    }
+
+    pub(crate) fn take(&self) -> Profile {
+        self.last_sample.set(Instant::now());
+        std::mem::take(&mut *self.profile.borrow_mut())
+    }
+}
+
+/// `name (file:line)` of a function, without the `;` folded stacks use
+/// as a separator.
+fn frame_name(debug: &mlua::Debug) -> String {
+    let source = debug.source();
+    let file = source.short_src.as_deref().unwrap_or("?");
+    let name = debug.names().name.as_deref().unwrap_or(source.what).to_string();
+    format!("{} ({}:{})", name, file, source.line_defined.unwrap_or(0)).replace(';', ",")
+}
+
+/// Time spent in each Lua call stack, in microseconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// Outermost function first.
+    pub stacks: HashMap<Vec<String>, u64>,
+}
+
+/// Time spent in one function, from [`Profile::functions`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionTime {
+    pub name: String,
+    /// Microseconds running the function itself.
+    pub self_micros: u64,
+    /// Microseconds with the function anywhere on the stack.
+    pub total_micros: u64,
+}
+
+impl Profile {
+    pub fn total_micros(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// Write one `outer;inner micros` line per stack, the format
+    /// `flamegraph.pl` and `inferno-flamegraph` read.
+    pub fn write_folded<W: Write>(&self, mut out: W) -> std::io::Result<()> {
+        let mut lines: Vec<(String, u64)> =
+            self.stacks.iter().map(|(stack, micros)| (stack.join(";"), *micros)).collect();
+        lines.sort();
+        for (stack, micros) in lines {
+            writeln!(out, "{} {}", stack, micros)?;
+        }
+        Ok(())
+    }
+
+    /// Every sampled function, by self time, longest first.
+    pub fn functions(&self) -> Vec<FunctionTime> {
+        let mut by_name: HashMap<&str, FunctionTime> = HashMap::new();
+        for (stack, micros) in &self.stacks {
+            for (depth, name) in stack.iter().enumerate() {
+                let time = by_name.entry(name).or_insert_with(|| FunctionTime {
+                    name: name.clone(),
+                    self_micros: 0,
+                    total_micros: 0,
+                });
+                // recursive functions count once per stack
+                if !stack[..depth].contains(name) {
+                    time.total_micros += micros;
+                }
+                if depth == stack.len() - 1 {
+                    time.self_micros += micros;
+                }
+            }
+        }
+        let mut functions: Vec<FunctionTime> = by_name.into_values().collect();
+        functions.sort_by(|a, b| (b.self_micros, b.total_micros, &a.name).cmp(&(a.self_micros, a.total_micros, &b.name)));
+        functions
+    }
+
+    /// A table of the `top` functions with the most self time.
+    pub fn summary(&self, top: usize) -> String {
+        let total = self.total_micros().max(1) as f64;
+        let mut summary = format!("{:>7} {:>10} {:>7} {:>10}  function\n", "self%", "self ms", "total%", "total ms");
+        for function in self.functions().into_iter().take(top) {
+            summary += &format!(
+                "{:>6.1}% {:>10.1} {:>6.1}% {:>10.1}  {}\n",
+                100.0 * function.self_micros as f64 / total,
+                function.self_micros as f64 / 1000.0,
+                100.0 * function.total_micros as f64 / total,
+                function.total_micros as f64 / 1000.0,
+                function.name
+            );
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_folds_stacks() {
+        let stack = |frames: &[&str]| frames.iter().map(|frame| frame.to_string()).collect::<Vec<_>>();
+        let profile = Profile {
+            stacks: HashMap::from([
+                (stack(&["main", "FindRoute", "RandomInt"]), 300),
+                (stack(&["main", "FindRoute"]), 100),
+                (stack(&["main"]), 50),
+            ]),
+        };
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 50\nmain;FindRoute 100\nmain;FindRoute;RandomInt 300\n");
+
+        let functions = profile.functions();
+        assert_eq!(functions[0], FunctionTime { name: "RandomInt".to_string(), self_micros: 300, total_micros: 300 });
+        assert_eq!(functions[1], FunctionTime { name: "FindRoute".to_string(), self_micros: 100, total_micros: 400 });
+        assert!(profile.summary(1).contains("RandomInt"));
+        assert!(!profile.summary(1).contains("FindRoute"));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::error::Error;
+use crate::simulator::Simulator;
+use mlua::{Function, Lua, MultiValue, Table, Value};
+use std::ffi::c_void;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// Tables nested deeper than this are shown as `{...}`.
+pub const PRETTY_DEPTH: usize = 3;
+
+const HELP: &str = "\
+:reset    reload the save and game scripts
+:history  show previous inputs
+:help     show this message
+:quit     leave (or end the input)";
+
+/// Read Lua from `input` and evaluate it in the simulation made by `build`,
+/// writing results to `output` like the `lua` interpreter does. Expressions
+/// print their values, statements just run, and incomplete input keeps
+/// reading lines until it parses. Inputs are appended to `history_path`.
+pub fn run<B, I, O>(mut build: B, mut input: I, mut output: O, history_path: Option<&Path>) -> Result<(), Error>
+where
+    B: FnMut() -> Result<Simulator, Error>,
+    I: BufRead,
+    O: Write,
+{
+    let mut sim = build()?;
+    let mut history = match history_path {
+        Some(path) => std::fs::read_to_string(path).map(|h| h.lines().map(String::from).collect()).unwrap_or_default(),
+        None => Vec::<String>::new(),
+    };
+
+    let mut pending = String::new();
+    loop {
+        write!(output, "{}", if pending.is_empty() { "> " } else { ">> " })?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(output)?;
+            return Ok(());
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+        if pending.is_empty() {
+            match line.trim() {
+                "" => continue,
+                ":quit" | ":q" => return Ok(()),
+                ":help" => {
+                    writeln!(output, "{}", HELP)?;
+                    continue;
+                }
+                ":history" => {
+                    for (i, entry) in history.iter().enumerate() {
+                        writeln!(output, "{:5}  {}", i + 1, entry.replace('\n', "\n       "))?;
+                    }
+                    continue;
+                }
+                ":reset" => {
+                    sim = build()?;
+                    writeln!(output, "Reloaded")?;
+                    continue;
+                }
+                command if command.starts_with(':') => {
+                    writeln!(output, "Unknown command {}; try :help", command)?;
+                    continue;
+                }
+                _ => {}
+            }
+            pending.push_str(line);
+        } else {
+            pending.push('\n');
+            pending.push_str(line);
+        }
+
+        let function = match compile(sim.lua(), &pending) {
+            Ok(function) => function,
+            Err(mlua::Error::SyntaxError { incomplete_input: true, .. }) => continue,
+            Err(err) => {
+                writeln!(output, "{}", err)?;
+                remember(&mut history, history_path, std::mem::take(&mut pending));
+                continue;
+            }
+        };
+        match function.call::<_, MultiValue>(()) {
+            Ok(values) => {
+                let values: Vec<String> = values.iter().map(|value| pretty(value, PRETTY_DEPTH)).collect();
+                if !values.is_empty() {
+                    writeln!(output, "{}", values.join("\t"))?;
+                }
+            }
+            Err(err) => writeln!(output, "{}", err)?,
+        }
+        remember(&mut history, history_path, std::mem::take(&mut pending));
+    }
+}
+
+/// Compile `source` as an expression whose value is printed, falling back
+/// to a statement.
+fn compile<'lua>(lua: &'lua Lua, source: &str) -> mlua::Result<Function<'lua>> {
+    lua.load(format!("return {}", source))
+        .set_name("=stdin")
+        .into_function()
+        .or_else(|_| lua.load(source).set_name("=stdin").into_function())
+}
+
+fn remember(history: &mut Vec<String>, history_path: Option<&Path>, entry: String) {
+    if let Some(path) = history_path {
+        let appended = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = appended {
+            eprintln!("Warning: Failed to write REPL history: {}", e);
+        }
+    }
+    history.push(entry);
+}
+
+/// Render a Lua value for reading, expanding tables up to `max_depth`
+/// levels with their keys sorted.
+pub fn pretty(value: &Value, max_depth: usize) -> String {
+    let mut out = String::new();
+    write_pretty(&mut out, value, 0, max_depth, &mut Vec::new());
+    out
+}
+
+fn write_pretty(out: &mut String, value: &Value, indent: usize, max_depth: usize, parents: &mut Vec<*const c_void>) {
+    match value {
+        Value::Nil => out.push_str("nil"),
+        Value::Boolean(b) => out.push_str(&b.to_string()),
+        Value::Integer(i) => out.push_str(&i.to_string()),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::String(s) => out.push_str(&format!("{:?}", s.to_string_lossy())),
+        Value::Table(table) => write_table(out, table, indent, max_depth, parents),
+        other => out.push_str(&format!("{}: {:?}", other.type_name(), other.to_pointer())),
+    }
+}
+
+fn write_table(out: &mut String, table: &Table, indent: usize, max_depth: usize, parents: &mut Vec<*const c_void>) {
+    if parents.contains(&table.to_pointer()) {
+        out.push_str("<cycle>");
+        return;
+    }
+    let mut entries: Vec<(Value, Value)> = table.clone().pairs().filter_map(Result::ok).collect();
+    if entries.is_empty() {
+        out.push_str("{}");
+        return;
+    }
+    if parents.len() >= max_depth {
+        out.push_str("{...}");
+        return;
+    }
+    entries.sort_by(|(a, _), (b, _)| key_order(a).partial_cmp(&key_order(b)).unwrap_or(std::cmp::Ordering::Equal));
+
+    parents.push(table.to_pointer());
+    out.push_str("{\n");
+    for (key, value) in &entries {
+        out.push_str(&"  ".repeat(indent + 1));
+        match key {
+            Value::String(s) if is_identifier(&s.to_string_lossy()) => out.push_str(&s.to_string_lossy()),
+            _ => {
+                out.push('[');
+                write_pretty(out, key, indent + 1, max_depth, parents);
+                out.push(']');
+            }
+        }
+        out.push_str(" = ");
+        write_pretty(out, value, indent + 1, max_depth, parents);
+        out.push_str(",\n");
+    }
+    out.push_str(&"  ".repeat(indent));
+    out.push('}');
+    parents.pop();
+}
+
+/// Numbers first in numeric order, then strings, then everything else.
+fn key_order(key: &Value) -> (u8, f64, String) {
+    match key {
+        Value::Integer(i) => (0, *i as f64, String::new()),
+        Value::Number(n) => (0, *n, String::new()),
+        Value::String(s) => (1, 0.0, s.to_string_lossy().into_owned()),
+        other => (2, 0.0, other.type_name().to_string()),
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repl_session() {
+        let input = "\
+x = 1
+x + 1
+function f()
+  return { Name = \"A_Combat01\", 2, 1 }
+end
+f()
+:reset
+x
+";
+        let mut output = Vec::new();
+        run(|| Simulator::builder().build(), input.as_bytes(), &mut output, None).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(
+            output.replace(">> ", "").replace("> ", ""),
+            "2\n{\n  [1] = 2,\n  [2] = 1,\n  Name = \"A_Combat01\",\n}\nReloaded\nnil\n\n"
+        );
+    }
+}
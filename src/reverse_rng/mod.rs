@@ -8,6 +8,7 @@ mod simd_avx2;
 mod simd_avx512;
 
 use crate::error::Error;
+use crate::rng::SggPcg;
 use std::path::PathBuf;
 
 pub fn run(input_file: PathBuf) -> Result<(), Error> {
@@ -19,9 +20,9 @@ pub fn run(input_file: PathBuf) -> Result<(), Error> {
     
     // Perform reverse engineering
     #[cfg(feature = "simd")]
-    let search_result = simd_search::find_original_state_simd(&data_points);
+    let search_result = simd_search::find_original_state_simd::<SggPcg>(&data_points);
     #[cfg(not(feature = "simd"))]
-    let search_result = search::find_original_state(&data_points);
+    let search_result = search::find_original_state::<SggPcg>(&data_points);
     
     match search_result {
         Ok(candidates) => {
@@ -1,6 +1,7 @@
 use crate::error::Error;
 use crate::reverse_rng::data_point::{DataPoint, StateCandidate};
-use crate::rng::SggPcg;
+use crate::rng::xsh_rr::{ROTATE, SPARE, XSHIFT};
+use crate::rng::GameRng;
 use std::time::Instant;
 use rayon::prelude::*;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -10,18 +11,8 @@ use std::arch::x86_64::*;
 
 pub const CHUNK_SIZE_AVX2: usize = 4; // Process 4 u64 states at once with AVX2
 
-// SIMD constants for PCG
-const MULTIPLIER: u64 = 0x5851f42d4c957f2d;
-const INCREMENT: u64 = 0xb47c73972972b7b7;
-const INITIAL_OFFSET: u64 = 0x3d657cc62bc341e;
-
-// PCG output function constants
-const ROTATE: u32 = 59;
-const XSHIFT: u32 = 18;
-const SPARE: u32 = 27;
-
 #[target_feature(enable = "avx2")]
-pub unsafe fn find_original_state_avx2(data_points: &[DataPoint]) -> Result<Vec<StateCandidate>, Error> {
+pub unsafe fn find_original_state_avx2<R: GameRng>(data_points: &[DataPoint]) -> Result<Vec<StateCandidate>, Error> {
     println!("Starting parallel AVX2 SIMD brute force search across 2^32 possible seeds...");
     
     let start_time = Instant::now();
@@ -63,14 +54,14 @@ pub unsafe fn find_original_state_avx2(data_points: &[DataPoint]) -> Result<Vec<
                 ];
                 
                 // Convert to u64 and compute initial PCG states using SIMD
-                let states = compute_initial_states_simd(&seeds);
+                let states = compute_initial_states_simd::<R>(&seeds);
                 
                 // Use SIMD to validate all 4 seeds at once
-                let valid_seeds = validate_seeds_simd_avx2(&seeds, &states, data_points);
+                let valid_seeds = validate_seeds_simd_avx2::<R>(&seeds, &states, data_points);
                 
                 for i in 0..CHUNK_SIZE_AVX2 {
                     if valid_seeds[i] {
-                        let state = SggPcg::new(seeds[i] as u64).state();
+                        let state = R::new(seeds[i] as u64).state();
                         
                         local_candidates.push(StateCandidate {
                             seed: seeds[i],
@@ -141,18 +132,18 @@ unsafe fn mul_epi64_avx2(a: __m256i, b: __m256i) -> __m256i {
 }
 
 #[target_feature(enable = "avx2")]
-unsafe fn compute_initial_states_simd(seeds: &[i32; CHUNK_SIZE_AVX2]) -> [u64; CHUNK_SIZE_AVX2] {
+unsafe fn compute_initial_states_simd<R: GameRng>(seeds: &[i32; CHUNK_SIZE_AVX2]) -> [u64; CHUNK_SIZE_AVX2] {
     // Load seeds into AVX2 register (4x i32 -> 4x u64)
     let seeds_128 = _mm_loadu_si128(seeds.as_ptr() as *const __m128i);
     let seeds_256 = _mm256_cvtepi32_epi64(seeds_128);
     
     // Load constants
-    let multiplier = _mm256_set1_epi64x(MULTIPLIER as i64);
-    let initial_offset = _mm256_set1_epi64x(INITIAL_OFFSET as i64);
+    let multiplier = _mm256_set1_epi64x(R::SEED_MULTIPLIER as i64);
+    let seed_increment = _mm256_set1_epi64x(R::SEED_INCREMENT as i64);
     
-    // Compute seeds * MULTIPLIER - INITIAL_OFFSET using custom 64-bit multiply
+    // Compute seeds * SEED_MULTIPLIER + SEED_INCREMENT using custom 64-bit multiply
     let product = mul_epi64_avx2(seeds_256, multiplier);
-    let states = _mm256_sub_epi64(product, initial_offset);
+    let states = _mm256_add_epi64(product, seed_increment);
     
     // Store result
     let mut result = [0u64; CHUNK_SIZE_AVX2];
@@ -161,13 +152,13 @@ unsafe fn compute_initial_states_simd(seeds: &[i32; CHUNK_SIZE_AVX2]) -> [u64; C
 }
 
 #[target_feature(enable = "avx2")]
-unsafe fn validate_seeds_simd_avx2(_seeds: &[i32; CHUNK_SIZE_AVX2], initial_states: &[u64; CHUNK_SIZE_AVX2], data_points: &[DataPoint]) -> [bool; CHUNK_SIZE_AVX2] {
+unsafe fn validate_seeds_simd_avx2<R: GameRng>(_seeds: &[i32; CHUNK_SIZE_AVX2], initial_states: &[u64; CHUNK_SIZE_AVX2], data_points: &[DataPoint]) -> [bool; CHUNK_SIZE_AVX2] {
     // Use i64 format where non-zero represents true, 0 represents false
     let mut results = [1i64; CHUNK_SIZE_AVX2]; // Start with all true (non-zero)
     
     // Load constants for SIMD operations
-    let multiplier = _mm256_set1_epi64x(MULTIPLIER as i64);
-    let increment = _mm256_set1_epi64x(INCREMENT as i64);
+    let multiplier = _mm256_set1_epi64x(R::MULTIPLIER as i64);
+    let increment = _mm256_set1_epi64x(R::INCREMENT as i64);
     
     // Process each data point
     for data_point in data_points {
@@ -176,7 +167,7 @@ unsafe fn validate_seeds_simd_avx2(_seeds: &[i32; CHUNK_SIZE_AVX2], initial_stat
         
         // Advance states if needed
         if data_point.offset > 0 {
-            states = advance_pcg_states_simd(states, data_point.offset - 1);
+            states = advance_pcg_states_simd::<R>(states, data_point.offset - 1);
         }
         
         // Generate next values using PCG
@@ -204,12 +195,12 @@ unsafe fn validate_seeds_simd_avx2(_seeds: &[i32; CHUNK_SIZE_AVX2], initial_stat
 }
 
 #[target_feature(enable = "avx2")]
-unsafe fn advance_pcg_states_simd(states: __m256i, delta: u64) -> __m256i {
+unsafe fn advance_pcg_states_simd<R: GameRng>(states: __m256i, delta: u64) -> __m256i {
     // Initialize accumulator vectors
     let mut acc_mult = _mm256_set1_epi64x(1);
     let mut acc_plus = _mm256_set1_epi64x(0);
-    let mut cur_mult = _mm256_set1_epi64x(MULTIPLIER as i64);
-    let mut cur_plus = _mm256_set1_epi64x(INCREMENT as i64);
+    let mut cur_mult = _mm256_set1_epi64x(R::MULTIPLIER as i64);
+    let mut cur_plus = _mm256_set1_epi64x(R::INCREMENT as i64);
     let mut mdelta = delta;
 
     while mdelta > 0 {
@@ -288,17 +279,11 @@ unsafe fn pcg_output_function_simd(states: __m256i) -> [u32; CHUNK_SIZE_AVX2] {
     result
 }
 
-// Shared utility functions for scalar fallback and testing
-pub fn pcg_output_function(state: u64) -> u32 {
-    let rot = (state >> ROTATE) as u32;
-    let xsh = (((state >> XSHIFT) ^ state) >> SPARE) as u32;
-    xsh.rotate_right(rot)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::reverse_rng::data_point::DataPoint;
+    use crate::rng::xsh_rr;
     
     #[test]
     fn test_early_termination_check_avx2() {
@@ -379,10 +364,10 @@ mod tests {
         
         // Compute expected results using scalar function
         let expected: [u32; CHUNK_SIZE_AVX2] = [
-            pcg_output_function(test_states[0]),
-            pcg_output_function(test_states[1]),
-            pcg_output_function(test_states[2]),
-            pcg_output_function(test_states[3]),
+            xsh_rr::output(test_states[0]),
+            xsh_rr::output(test_states[1]),
+            xsh_rr::output(test_states[2]),
+            xsh_rr::output(test_states[3]),
         ];
         
         // Compute using SIMD function
@@ -1,6 +1,7 @@
 use crate::error::Error;
 use crate::reverse_rng::data_point::{DataPoint, StateCandidate};
-use crate::rng::SggPcg;
+use crate::rng::xsh_rr::{ROTATE, SPARE, XSHIFT};
+use crate::rng::GameRng;
 use std::time::Instant;
 use rayon::prelude::*;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -10,18 +11,8 @@ use std::arch::x86_64::*;
 
 pub const CHUNK_SIZE_AVX512: usize = 8; // Process 8 u64 states at once with AVX-512
 
-// SIMD constants for PCG
-const MULTIPLIER: u64 = 0x5851f42d4c957f2d;
-const INCREMENT: u64 = 0xb47c73972972b7b7;
-const INITIAL_OFFSET: u64 = 0x3d657cc62bc341e;
-
-// PCG output function constants
-const ROTATE: u32 = 59;
-const XSHIFT: u32 = 18;
-const SPARE: u32 = 27;
-
 #[target_feature(enable = "avx512f")]
-pub unsafe fn find_original_state_avx512(data_points: &[DataPoint]) -> Result<Vec<StateCandidate>, Error> {
+pub unsafe fn find_original_state_avx512<R: GameRng>(data_points: &[DataPoint]) -> Result<Vec<StateCandidate>, Error> {
     println!("Starting parallel AVX-512 SIMD brute force search across 2^32 possible seeds...");
     
     let start_time = Instant::now();
@@ -67,14 +58,14 @@ pub unsafe fn find_original_state_avx512(data_points: &[DataPoint]) -> Result<Ve
                 ];
                 
                 // Convert to u64 and compute initial PCG states using SIMD
-                let states = compute_initial_states_simd_avx512(&seeds);
+                let states = compute_initial_states_simd_avx512::<R>(&seeds);
                 
                 // Use SIMD to validate all 8 seeds at once
-                let valid_seeds = validate_seeds_simd_avx512(&seeds, &states, data_points);
+                let valid_seeds = validate_seeds_simd_avx512::<R>(&seeds, &states, data_points);
                 
                 for i in 0..CHUNK_SIZE_AVX512 {
                     if valid_seeds[i] {
-                        let state = SggPcg::new(seeds[i] as u64).state();
+                        let state = R::new(seeds[i] as u64).state();
                         
                         local_candidates.push(StateCandidate {
                             seed: seeds[i],
@@ -122,18 +113,18 @@ pub unsafe fn find_original_state_avx512(data_points: &[DataPoint]) -> Result<Ve
 }
 
 #[target_feature(enable = "avx512f")]
-unsafe fn compute_initial_states_simd_avx512(seeds: &[i32; CHUNK_SIZE_AVX512]) -> [u64; CHUNK_SIZE_AVX512] {
+unsafe fn compute_initial_states_simd_avx512<R: GameRng>(seeds: &[i32; CHUNK_SIZE_AVX512]) -> [u64; CHUNK_SIZE_AVX512] {
     // Load seeds into AVX-512 register (8x i32 -> 8x u64)
     let seeds_256 = _mm256_loadu_si256(seeds.as_ptr() as *const __m256i);
     let seeds_512 = _mm512_cvtepi32_epi64(seeds_256);
     
     // Load constants
-    let multiplier = _mm512_set1_epi64(MULTIPLIER as i64);
-    let initial_offset = _mm512_set1_epi64(INITIAL_OFFSET as i64);
+    let multiplier = _mm512_set1_epi64(R::SEED_MULTIPLIER as i64);
+    let seed_increment = _mm512_set1_epi64(R::SEED_INCREMENT as i64);
     
-    // Compute seeds * MULTIPLIER - INITIAL_OFFSET using built-in 64-bit multiply
+    // Compute seeds * SEED_MULTIPLIER + SEED_INCREMENT using built-in 64-bit multiply
     let product = _mm512_mullox_epi64(seeds_512, multiplier);
-    let states = _mm512_sub_epi64(product, initial_offset);
+    let states = _mm512_add_epi64(product, seed_increment);
     
     // Store result
     let mut result = [0u64; CHUNK_SIZE_AVX512];
@@ -142,13 +133,13 @@ unsafe fn compute_initial_states_simd_avx512(seeds: &[i32; CHUNK_SIZE_AVX512]) -
 }
 
 #[target_feature(enable = "avx512f")]
-unsafe fn validate_seeds_simd_avx512(_seeds: &[i32; CHUNK_SIZE_AVX512], initial_states: &[u64; CHUNK_SIZE_AVX512], data_points: &[DataPoint]) -> [bool; CHUNK_SIZE_AVX512] {
+unsafe fn validate_seeds_simd_avx512<R: GameRng>(_seeds: &[i32; CHUNK_SIZE_AVX512], initial_states: &[u64; CHUNK_SIZE_AVX512], data_points: &[DataPoint]) -> [bool; CHUNK_SIZE_AVX512] {
     // Use i64 format where non-zero represents true, 0 represents false
     let mut results = [1i64; CHUNK_SIZE_AVX512]; // Start with all true (non-zero)
     
     // Load constants for SIMD operations
-    let multiplier = _mm512_set1_epi64(MULTIPLIER as i64);
-    let increment = _mm512_set1_epi64(INCREMENT as i64);
+    let multiplier = _mm512_set1_epi64(R::MULTIPLIER as i64);
+    let increment = _mm512_set1_epi64(R::INCREMENT as i64);
     
     // Process each data point
     for data_point in data_points {
@@ -157,7 +148,7 @@ unsafe fn validate_seeds_simd_avx512(_seeds: &[i32; CHUNK_SIZE_AVX512], initial_
         
         // Advance states if needed
         if data_point.offset > 0 {
-            states = advance_pcg_states_simd_avx512(states, data_point.offset - 1);
+            states = advance_pcg_states_simd_avx512::<R>(states, data_point.offset - 1);
         }
         
         // Generate next values using PCG
@@ -189,12 +180,12 @@ unsafe fn validate_seeds_simd_avx512(_seeds: &[i32; CHUNK_SIZE_AVX512], initial_
 }
 
 #[target_feature(enable = "avx512f")]
-unsafe fn advance_pcg_states_simd_avx512(states: __m512i, delta: u64) -> __m512i {
+unsafe fn advance_pcg_states_simd_avx512<R: GameRng>(states: __m512i, delta: u64) -> __m512i {
     // Initialize accumulator vectors
     let mut acc_mult = _mm512_set1_epi64(1);
     let mut acc_plus = _mm512_set1_epi64(0);
-    let mut cur_mult = _mm512_set1_epi64(MULTIPLIER as i64);
-    let mut cur_plus = _mm512_set1_epi64(INCREMENT as i64);
+    let mut cur_mult = _mm512_set1_epi64(R::MULTIPLIER as i64);
+    let mut cur_plus = _mm512_set1_epi64(R::INCREMENT as i64);
     let mut mdelta = delta;
 
     while mdelta > 0 {
@@ -274,17 +265,11 @@ unsafe fn pcg_output_function_simd_avx512(states: __m512i) -> [u32; CHUNK_SIZE_A
     result
 }
 
-// Shared utility functions for scalar fallback and testing
-pub fn pcg_output_function(state: u64) -> u32 {
-    let rot = (state >> ROTATE) as u32;
-    let xsh = (((state >> XSHIFT) ^ state) >> SPARE) as u32;
-    xsh.rotate_right(rot)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::reverse_rng::data_point::DataPoint;
+    use crate::rng::xsh_rr;
     
     #[test]
     fn test_avx512_early_termination_check() {
@@ -331,14 +316,14 @@ mod tests {
         
         // Compute expected results using scalar function
         let expected: [u32; CHUNK_SIZE_AVX512] = [
-            pcg_output_function(test_states[0]),
-            pcg_output_function(test_states[1]),
-            pcg_output_function(test_states[2]),
-            pcg_output_function(test_states[3]),
-            pcg_output_function(test_states[4]),
-            pcg_output_function(test_states[5]),
-            pcg_output_function(test_states[6]),
-            pcg_output_function(test_states[7]),
+            xsh_rr::output(test_states[0]),
+            xsh_rr::output(test_states[1]),
+            xsh_rr::output(test_states[2]),
+            xsh_rr::output(test_states[3]),
+            xsh_rr::output(test_states[4]),
+            xsh_rr::output(test_states[5]),
+            xsh_rr::output(test_states[6]),
+            xsh_rr::output(test_states[7]),
         ];
         
         // Compute using SIMD function
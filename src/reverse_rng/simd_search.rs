@@ -1,30 +1,31 @@
 use crate::error::Error;
 use crate::reverse_rng::data_point::{DataPoint, StateCandidate};
+use crate::rng::GameRng;
 
 #[cfg(all(feature = "simd", target_arch = "x86_64"))]
 use crate::reverse_rng::simd_avx2::find_original_state_avx2;
 #[cfg(all(feature = "simd_nightly", target_arch = "x86_64"))]
 use crate::reverse_rng::simd_avx512::find_original_state_avx512;
 
-pub fn find_original_state_simd(data_points: &[DataPoint]) -> Result<Vec<StateCandidate>, Error> {
+pub fn find_original_state_simd<R: GameRng>(data_points: &[DataPoint]) -> Result<Vec<StateCandidate>, Error> {
     #[cfg(feature = "simd")]
-    {
+    if R::XSH_RR_OUTPUT {
         #[cfg(feature = "simd_nightly")]
         {
             if is_x86_feature_detected!("avx512f") {
                 println!("Using AVX-512 SIMD optimization for brute force search...");
-                return unsafe { find_original_state_avx512(data_points) };
+                return unsafe { find_original_state_avx512::<R>(data_points) };
             }
         }
 
         if is_x86_feature_detected!("avx2") {
             println!("Using AVX2 SIMD optimization for brute force search...");
-            return unsafe { find_original_state_avx2(data_points) };
+            return unsafe { find_original_state_avx2::<R>(data_points) };
         }
     }
     
     println!("SIMD not available or not enabled, falling back to scalar implementation");
-    crate::reverse_rng::search::find_original_state(data_points)
+    crate::reverse_rng::search::find_original_state::<R>(data_points)
 }
 
 #[cfg(test)]
@@ -63,8 +64,8 @@ mod tests {
         }
         
         // Test SIMD implementation finds the same result as scalar
-        let simd_result = find_original_state_simd(&data_points).unwrap();
-        let scalar_result = crate::reverse_rng::search::find_original_state(&data_points).unwrap();
+        let simd_result = find_original_state_simd::<SggPcg>(&data_points).unwrap();
+        let scalar_result = crate::reverse_rng::search::find_original_state::<SggPcg>(&data_points).unwrap();
         
         assert_eq!(simd_result.len(), scalar_result.len());
         if !simd_result.is_empty() {
@@ -7,14 +7,98 @@
 // and licensed under the MIT license.
 
 use core::fmt;
+use libm::ldexp;
 use rand_core::{impls, Error, RngCore};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
 // This is the default multiplier used by PCG for 64-bit state.
 const MULTIPLIER: u64 = 0x5851f42d4c957f2d;
 const INCREMENT: u64 = 0xb47c73972972b7b7;
 const INITIAL_OFFSET: u64 = 0x3d657cc62bc341e;
 
+// The LCG run backwards: MULTIPLIER * INVERSE_MULTIPLIER == 1 (mod 2^64)
+// and INVERSE_INCREMENT == -INVERSE_MULTIPLIER * INCREMENT (mod 2^64).
+const INVERSE_MULTIPLIER: u64 = 0xc097ef87329e28a5;
+const INVERSE_INCREMENT: u64 = 0xca6229e83b68ff0d;
+
+/// A generator that can drive the engine's RNG streams and the reverse
+/// search: a 64-bit LCG state stepped by an affine map, seeded by an
+/// affine map of the seed, with a 32-bit output function. `SggPcg` is the
+/// generator Hades uses.
+pub trait GameRng: RngCore + Clone + fmt::Debug {
+    /// One step is `state * MULTIPLIER + INCREMENT`.
+    const MULTIPLIER: u64;
+    const INCREMENT: u64;
+    /// Seeding sets the state to `seed * SEED_MULTIPLIER + SEED_INCREMENT`.
+    const SEED_MULTIPLIER: u64;
+    const SEED_INCREMENT: u64;
+    /// Whether `output` is PCG XSH RR 64/32, the only output function the
+    /// SIMD search backends implement.
+    const XSH_RR_OUTPUT: bool;
+
+    fn new(seed: u64) -> Self;
+    fn state(&self) -> u64;
+    /// Number of outputs drawn since seeding.
+    fn uses(&self) -> i64;
+    fn advance(&mut self, delta: u64);
+    fn retreat(&mut self, delta: u64);
+    /// The value `next_u32()` returns when the generator is in `state`.
+    fn output(state: u64) -> u32;
+
+    fn seed_state(seed: u64) -> u64 {
+        seed.wrapping_mul(Self::SEED_MULTIPLIER).wrapping_add(Self::SEED_INCREMENT)
+    }
+
+    /// `(mult, plus)` such that advancing by `delta` takes `state` to
+    /// `state * mult + plus`.
+    fn jump_parameters(delta: u64) -> (u64, u64) {
+        jump_parameters(delta, Self::MULTIPLIER, Self::INCREMENT)
+    }
+}
+
+/// The PCG XSH RR 64/32 output function: xorshift high (bits), followed
+/// by a random rotate.
+pub mod xsh_rr {
+    // Constants are for 64-bit state, 32-bit output
+    pub const ROTATE: u32 = 59; // 64 - 5
+    pub const XSHIFT: u32 = 18; // (5 + 32) / 2
+    pub const SPARE: u32 = 27; // 64 - 32 - 5
+
+    #[inline]
+    pub fn output(state: u64) -> u32 {
+        let rot = (state >> ROTATE) as u32;
+        let xsh = (((state >> XSHIFT) ^ state) >> SPARE) as u32;
+        xsh.rotate_right(rot)
+    }
+}
+
+/// Multi-step advance parameters for the LCG `state * mult + plus`.
+///
+/// The method used here is based on Brown, "Random Number Generation
+/// with Arbitrary Stride,", Transactions of the American Nuclear
+/// Society (Nov. 1994).  The algorithm is very similar to fast
+/// exponentiation.
+pub fn jump_parameters(delta: u64, mult: u64, plus: u64) -> (u64, u64) {
+    let mut acc_mult: u64 = 1;
+    let mut acc_plus: u64 = 0;
+    let mut cur_mult = mult;
+    let mut cur_plus = plus;
+    let mut mdelta = delta;
+
+    while mdelta > 0 {
+        if (mdelta & 1) != 0 {
+            acc_mult = acc_mult.wrapping_mul(cur_mult);
+            acc_plus = acc_plus.wrapping_mul(cur_mult).wrapping_add(cur_plus);
+        }
+        cur_plus = cur_mult.wrapping_add(1).wrapping_mul(cur_plus);
+        cur_mult = cur_mult.wrapping_mul(cur_mult);
+        mdelta /= 2;
+    }
+    (acc_mult, acc_plus)
+}
+
 /// A PCG random number generator (XSH RR 64/32 (LCG) variant).
 ///
 /// Permuted Congruential Generator with 64-bit state, internal Linear
@@ -33,44 +117,44 @@ const INITIAL_OFFSET: u64 = 0x3d657cc62bc341e;
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SggPcg {
     state: u64,
+    /// Number of outputs drawn since seeding, what the game calls "uses".
+    #[serde(default)]
+    uses: i64,
 }
 
 impl SggPcg {
-    /// Multi-step advance functions (jump-ahead, jump-back)
-    ///
-    /// The method used here is based on Brown, "Random Number Generation
-    /// with Arbitrary Stride,", Transactions of the American Nuclear
-    /// Society (Nov. 1994).  The algorithm is very similar to fast
-    /// exponentiation.
+    /// Multi-step advance functions (jump-ahead, jump-back), see
+    /// `jump_parameters`.
     ///
     /// Even though delta is an unsigned integer, we can pass a
     /// signed integer to go backwards, it just goes "the long way round".
+    /// `retreat` goes backwards directly.
     ///
     /// Using this function is equivalent to calling `next_32()` `delta`
     /// number of times.
     #[inline]
     pub fn advance(&mut self, delta: u64) {
-        let mut acc_mult: u64 = 1;
-        let mut acc_plus: u64 = 0;
-        let mut cur_mult = MULTIPLIER;
-        let mut cur_plus = INCREMENT;
-        let mut mdelta = delta;
-
-        while mdelta > 0 {
-            if (mdelta & 1) != 0 {
-                acc_mult = acc_mult.wrapping_mul(cur_mult);
-                acc_plus = acc_plus.wrapping_mul(cur_mult).wrapping_add(cur_plus);
-            }
-            cur_plus = cur_mult.wrapping_add(1).wrapping_mul(cur_plus);
-            cur_mult = cur_mult.wrapping_mul(cur_mult);
-            mdelta /= 2;
-        }
+        self.jump(delta, MULTIPLIER, INCREMENT);
+        self.uses = self.uses.wrapping_add(delta as i64);
+    }
+
+    /// Undo `delta` calls to `next_u32()`, using the inverse of the LCG.
+    #[inline]
+    pub fn retreat(&mut self, delta: u64) {
+        self.jump(delta, INVERSE_MULTIPLIER, INVERSE_INCREMENT);
+        self.uses = self.uses.wrapping_sub(delta as i64);
+    }
+
+    #[inline]
+    fn jump(&mut self, delta: u64, mult: u64, plus: u64) {
+        let (acc_mult, acc_plus) = jump_parameters(delta, mult, plus);
         self.state = acc_mult.wrapping_mul(self.state).wrapping_add(acc_plus);
     }
 
     pub fn new(seed: u64) -> Self {
         SggPcg {
-            state: seed.wrapping_mul(MULTIPLIER).wrapping_sub(INITIAL_OFFSET),
+            state: <SggPcg as GameRng>::seed_state(seed),
+            uses: 0,
         }
     }
 
@@ -78,29 +162,114 @@ impl SggPcg {
     fn step(&mut self) {
         // prepare the LCG for the next round
         self.state = self.state.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT);
+        self.uses = self.uses.wrapping_add(1);
     }
 
-    pub fn save_to_file(&self, path: &str) -> Result<(), std::io::Error> {
-        let json = serde_json::to_string_pretty(self)?;
-        std::fs::write(path, json)?;
-        Ok(())
+    /// Step backwards and return the value the last `next_u32()` returned,
+    /// so that `next_u32()` followed by `prev_u32()` leaves the state
+    /// unchanged.
+    #[inline]
+    pub fn prev_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(INVERSE_MULTIPLIER).wrapping_add(INVERSE_INCREMENT);
+        self.uses = self.uses.wrapping_sub(1);
+        xsh_rr::output(self.state)
     }
 
-    pub fn load_from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let json = std::fs::read_to_string(path)?;
-        let rng: SggPcg = serde_json::from_str(&json)?;
-        Ok(rng)
+    /// Iterate over the next `count` outputs without touching this
+    /// generator. The iterator is double ended, so `.rev()` walks from the
+    /// last of those outputs back to the first.
+    pub fn outputs(&self, count: u64) -> Outputs {
+        let mut back = self.clone();
+        back.advance(count);
+        Outputs {
+            front: self.clone(),
+            back,
+            remaining: count,
+        }
     }
 
     pub fn state(&self) -> u64 {
         self.state
     }
+
+    /// Number of outputs drawn since seeding. Negative if the generator
+    /// was stepped back past the seed.
+    pub fn uses(&self) -> i64 {
+        self.uses
+    }
+}
+
+/// Iterator over a fixed window of outputs, see `SggPcg::outputs`.
+#[derive(Clone, Debug)]
+pub struct Outputs {
+    front: SggPcg,
+    back: SggPcg,
+    remaining: u64,
+}
+
+impl Iterator for Outputs {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.front.next_u32())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = usize::try_from(self.remaining).unwrap_or(usize::MAX);
+        (remaining, usize::try_from(self.remaining).ok())
+    }
+}
+
+impl DoubleEndedIterator for Outputs {
+    fn next_back(&mut self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.back.prev_u32())
+    }
+}
+
+impl GameRng for SggPcg {
+    const MULTIPLIER: u64 = MULTIPLIER;
+    const INCREMENT: u64 = INCREMENT;
+    const SEED_MULTIPLIER: u64 = MULTIPLIER;
+    const SEED_INCREMENT: u64 = INITIAL_OFFSET.wrapping_neg();
+    const XSH_RR_OUTPUT: bool = true;
+
+    fn new(seed: u64) -> Self {
+        SggPcg::new(seed)
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn uses(&self) -> i64 {
+        self.uses
+    }
+
+    fn advance(&mut self, delta: u64) {
+        SggPcg::advance(self, delta)
+    }
+
+    fn retreat(&mut self, delta: u64) {
+        SggPcg::retreat(self, delta)
+    }
+
+    fn output(state: u64) -> u32 {
+        xsh_rr::output(state)
+    }
 }
 
 // Custom Debug implementation that does not expose the internal state
 impl fmt::Debug for SggPcg {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SggPcg {{}}")
+        write!(f, "SggPcg {{ uses: {} }}", self.uses)
     }
 }
 
@@ -109,16 +278,7 @@ impl RngCore for SggPcg {
     fn next_u32(&mut self) -> u32 {
         let state = self.state;
         self.step();
-
-        // Output function XSH RR: xorshift high (bits), followed by a random rotate
-        // Constants are for 64-bit state, 32-bit output
-        const ROTATE: u32 = 59; // 64 - 5
-        const XSHIFT: u32 = 18; // (5 + 32) / 2
-        const SPARE: u32 = 27; // 64 - 32 - 5
-
-        let rot = (state >> ROTATE) as u32;
-        let xsh = (((state >> XSHIFT) ^ state) >> SPARE) as u32;
-        xsh.rotate_right(rot)
+        xsh_rr::output(state)
     }
 
     #[inline]
@@ -137,3 +297,162 @@ impl RngCore for SggPcg {
         Ok(())
     }
 }
+
+/// The set of independent generators the engine keeps, keyed by the id
+/// passed to `randomseed(seed, id)`, `randomint(min, max, id)` and
+/// `random(id)`, always in that position.
+///
+/// Calls that don't pass an id use the global stream, which is the first
+/// stream the scripts seed (`RandomInit`). Streams that have never been
+/// seeded behave as if seeded with 0.
+#[derive(Clone, Debug)]
+pub struct RngStreams<R: GameRng = SggPcg> {
+    streams: HashMap<i32, R>,
+    global_id: Option<i32>,
+}
+
+impl<R: GameRng> Default for RngStreams<R> {
+    fn default() -> Self {
+        RngStreams {
+            streams: HashMap::new(),
+            global_id: None,
+        }
+    }
+}
+
+impl<R: GameRng> RngStreams<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve an optional stream id to the id of the stream it refers to.
+    pub fn resolve(&self, id: Option<i32>) -> i32 {
+        id.or(self.global_id).unwrap_or(0)
+    }
+
+    pub fn seed(&mut self, seed: u64, id: Option<i32>) -> i32 {
+        let id = self.resolve(id);
+        self.global_id.get_or_insert(id);
+        self.streams.insert(id, R::new(seed));
+        id
+    }
+
+    pub fn get(&mut self, id: Option<i32>) -> &mut R {
+        let id = self.resolve(id);
+        self.streams.entry(id).or_insert_with(|| R::new(0))
+    }
+}
+
+/// Draw an integer in `[min, max]` the way the engine's `randomint` does.
+pub fn rand_int<R: RngCore>(rng: &mut R, min: i32, max: i32) -> i32 {
+    // println!("rand_int min {} max {}", min, max);
+    if max > min {
+        let bound = (max as u32).wrapping_sub(min as u32).wrapping_add(1);
+        min.wrapping_add(bounded(rng, bound) as i32)
+    } else {
+        rng.next_u32(); // advance and ignore result (to keep in sync)
+        min
+    }
+}
+
+fn bounded<R: RngCore>(rng: &mut R, bound: u32) -> u32 {
+    let threshold = (u32::MAX - bound + 1) % bound;
+
+    loop {
+        let r = rng.next_u32();
+        if r >= threshold {
+            return r % bound;
+        }
+    }
+}
+
+/// Draw a float in `[0, 1)` the way the engine's `random` does.
+pub fn rand_double<R: RngCore>(rng: &mut R) -> f64 {
+    ldexp(rng.next_u32() as f64, -32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streams_are_independent() {
+        let mut streams: RngStreams = RngStreams::new();
+        streams.seed(42, Some(1));
+        streams.seed(7, Some(2));
+
+        let mut expected = SggPcg::new(42);
+        let first = streams.get(Some(1)).next_u32();
+        // drawing from (and reseeding) another stream must not disturb stream 1
+        streams.get(Some(2)).next_u32();
+        streams.seed(99, Some(2));
+        let second = streams.get(Some(1)).next_u32();
+
+        assert_eq!(first, expected.next_u32());
+        assert_eq!(second, expected.next_u32());
+    }
+
+    #[test]
+    fn test_retreat_undoes_advance() {
+        let mut rng = SggPcg::new(12345);
+        let start = rng.clone();
+        rng.advance(1_000_003);
+        rng.retreat(1_000_003);
+        assert_eq!(rng, start);
+
+        let value = rng.next_u32();
+        assert_eq!(rng.prev_u32(), value);
+        assert_eq!(rng, start);
+    }
+
+    #[test]
+    fn test_jump_parameters_match_stepping() {
+        let mut rng = SggPcg::new(99);
+        let (mult, plus) = <SggPcg as GameRng>::jump_parameters(37);
+        let expected = rng.state().wrapping_mul(mult).wrapping_add(plus);
+        for _ in 0..37 {
+            let state = rng.state();
+            assert_eq!(<SggPcg as GameRng>::output(state), rng.next_u32());
+        }
+        assert_eq!(rng.state(), expected);
+    }
+
+    #[test]
+    fn test_uses_counts_draws() {
+        let mut rng = SggPcg::new(7);
+        rng.next_u32();
+        rng.next_u32();
+        rng.advance(10);
+        assert_eq!(rng.uses(), 12);
+        rng.prev_u32();
+        rng.retreat(5);
+        assert_eq!(rng.uses(), 6);
+        rand_int(&mut rng, 1, 16);
+        assert!(rng.uses() >= 7);
+    }
+
+    #[test]
+    fn test_outputs_reversed() {
+        let rng = SggPcg::new(42);
+        let forwards: Vec<u32> = rng.outputs(20).collect();
+        let mut backwards: Vec<u32> = rng.outputs(20).rev().collect();
+        backwards.reverse();
+        assert_eq!(forwards, backwards);
+
+        let mut outputs = rng.outputs(3);
+        assert_eq!(outputs.next(), Some(forwards[0]));
+        assert_eq!(outputs.next_back(), Some(forwards[2]));
+        assert_eq!(outputs.next_back(), Some(forwards[1]));
+        assert_eq!(outputs.next(), None);
+    }
+
+    #[test]
+    fn test_missing_id_uses_first_seeded_stream() {
+        let mut streams: RngStreams = RngStreams::new();
+        streams.seed(42, Some(3));
+        streams.seed(7, Some(5));
+
+        assert_eq!(streams.resolve(None), 3);
+        assert_eq!(streams.get(None).next_u32(), SggPcg::new(42).next_u32());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::error::Error;
+use crate::rng::{rand_double, rand_int, SggPcg};
+use rand_core::RngCore;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a draw is rendered, mirroring the game's helpers:
+/// `int:MIN:MAX` is `RandomInt`, `float` / `float:MIN:MAX` is `RandomFloat`
+/// and `chance:P` is `RandomChance`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrawKind {
+    Raw,
+    Int { min: i32, max: i32 },
+    Float { min: f64, max: f64 },
+    Chance(f64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrawValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+/// A condition on a draw, written `KIND OP VALUE`, e.g. `int:1:16<=4`.
+/// A bare `chance:P` means the chance succeeds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub kind: DrawKind,
+    pub comparison: Comparison,
+    pub value: DrawValue,
+}
+
+impl DrawKind {
+    /// Draw from a copy of `rng`, leaving it untouched. Returns the raw
+    /// u32 that the draw started with along with the rendered value.
+    pub fn peek(&self, rng: &SggPcg) -> (u32, DrawValue) {
+        let raw = rng.clone().next_u32();
+        let mut rng = rng.clone();
+        let value = match *self {
+            DrawKind::Raw => DrawValue::Int(rng.next_u32() as i64),
+            DrawKind::Int { min, max } => DrawValue::Int(rand_int(&mut rng, min, max) as i64),
+            DrawKind::Float { min, max } => DrawValue::Float(min + rand_double(&mut rng) * (max - min)),
+            DrawKind::Chance(chance) => DrawValue::Bool(rand_double(&mut rng) <= chance),
+        };
+        (raw, value)
+    }
+}
+
+impl FromStr for DrawKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let parts: Vec<&str> = s.trim().split(':').collect();
+        let number = |part: &str| {
+            part.parse::<f64>()
+                .map_err(|_| Error::from(format!("Invalid number '{}' in draw '{}'", part, s)))
+        };
+        let integer = |part: &str| {
+            part.parse::<i32>()
+                .map_err(|_| Error::from(format!("Invalid integer '{}' in draw '{}'", part, s)))
+        };
+        match parts.as_slice() {
+            ["raw"] => Ok(DrawKind::Raw),
+            ["int", min, max] => Ok(DrawKind::Int { min: integer(min)?, max: integer(max)? }),
+            ["float"] => Ok(DrawKind::Float { min: 0.0, max: 1.0 }),
+            ["float", min, max] => Ok(DrawKind::Float { min: number(min)?, max: number(max)? }),
+            ["chance", chance] => Ok(DrawKind::Chance(number(chance)?)),
+            _ => Err(Error::from(format!(
+                "Invalid draw '{}': expected raw, int:MIN:MAX, float, float:MIN:MAX or chance:P",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DrawValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DrawValue::Int(i) => write!(f, "{}", i),
+            DrawValue::Float(x) => write!(f, "{:.6}", x),
+            DrawValue::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl Comparison {
+    fn holds<T: PartialOrd>(&self, lhs: T, rhs: T) -> bool {
+        match self {
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Ge => lhs >= rhs,
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+        }
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, value: DrawValue) -> bool {
+        match (value, self.value) {
+            (DrawValue::Int(lhs), DrawValue::Int(rhs)) => self.comparison.holds(lhs, rhs),
+            (DrawValue::Int(lhs), DrawValue::Float(rhs)) => self.comparison.holds(lhs as f64, rhs),
+            (DrawValue::Float(lhs), DrawValue::Float(rhs)) => self.comparison.holds(lhs, rhs),
+            (DrawValue::Float(lhs), DrawValue::Int(rhs)) => self.comparison.holds(lhs, rhs as f64),
+            (DrawValue::Bool(lhs), DrawValue::Bool(rhs)) => self.comparison.holds(lhs, rhs),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        // two character operators first so that `<=` isn't read as `<`
+        const OPERATORS: [(&str, Comparison); 6] = [
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ];
+        let found = OPERATORS
+            .iter()
+            .filter_map(|(op, comparison)| s.find(op).map(|i| (i, *op, *comparison)))
+            .min_by_key(|(i, op, _)| (*i, usize::MAX - op.len()));
+
+        let (kind, comparison, value) = match found {
+            Some((i, op, comparison)) => (s[..i].parse::<DrawKind>()?, comparison, s[i + op.len()..].trim()),
+            None => (s.parse::<DrawKind>()?, Comparison::Eq, "true"),
+        };
+        let value = match kind {
+            DrawKind::Chance(_) => match value {
+                "true" => DrawValue::Bool(true),
+                "false" => DrawValue::Bool(false),
+                _ => return Err(Error::from(format!("Invalid condition '{}': chance draws compare to true or false", s))),
+            },
+            _ => match value.parse::<i64>() {
+                Ok(i) => DrawValue::Int(i),
+                Err(_) => DrawValue::Float(value.parse::<f64>().map_err(|_| {
+                    Error::from(format!("Invalid condition '{}': '{}' is not a number", s, value))
+                })?),
+            },
+        };
+        if let (DrawKind::Chance(_), Comparison::Lt | Comparison::Le | Comparison::Gt | Comparison::Ge) =
+            (kind, comparison)
+        {
+            return Err(Error::from(format!("Invalid condition '{}': chance draws only support == and !=", s)));
+        }
+        Ok(Condition { kind, comparison, value })
+    }
+}
+
+/// Offsets in `start..start + limit` from `rng` whose draw satisfies `condition`.
+pub fn find(rng: &SggPcg, condition: &Condition, start: u64, limit: u64) -> Vec<(u64, u32, DrawValue)> {
+    let mut rng = rng.clone();
+    rng.advance(start);
+    let mut found = Vec::new();
+    for offset in start..start.saturating_add(limit) {
+        let (raw, value) = condition.kind.peek(&rng);
+        if condition.matches(value) {
+            found.push((offset, raw, value));
+        }
+        rng.next_u32();
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_condition() {
+        let condition: Condition = "int:1:16<=4".parse().unwrap();
+        assert_eq!(condition.kind, DrawKind::Int { min: 1, max: 16 });
+        assert_eq!(condition.comparison, Comparison::Le);
+        assert_eq!(condition.value, DrawValue::Int(4));
+
+        let condition: Condition = "chance:0.25".parse().unwrap();
+        assert_eq!(condition.kind, DrawKind::Chance(0.25));
+        assert_eq!(condition.value, DrawValue::Bool(true));
+
+        assert!("int:1<=4".parse::<Condition>().is_err());
+        assert!("chance:0.25<true".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_find_matches_peek() {
+        let rng = SggPcg::new(12345);
+        let condition: Condition = "int:1:16<=4".parse().unwrap();
+        let found = find(&rng, &condition, 10, 200);
+        assert!(!found.is_empty());
+        for (offset, _, value) in found {
+            let mut at_offset = rng.clone();
+            at_offset.advance(offset);
+            assert_eq!(condition.kind.peek(&at_offset).1, value);
+            assert!(matches!(value, DrawValue::Int(i) if (1..=4).contains(&i)));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::error::Error;
+use crate::rng::SggPcg;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_SLOT: &str = "default";
+
+/// One set, advance or undo applied to a slot, with the state it replaced
+/// so that it can be undone.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub operation: String,
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub before: Option<SggPcg>,
+    /// For an undo, the index of the entry it reverted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub undoes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Slot {
+    /// None once the operation that created the slot is undone; the slot
+    /// stays to keep its history.
+    pub rng: Option<SggPcg>,
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+/// Named RNG states kept in the `rng` command's state file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RngSlots {
+    pub slots: BTreeMap<String, Slot>,
+}
+
+impl RngSlots {
+    /// Load the state file, or start empty if it doesn't exist yet. State
+    /// files written before slots existed hold a single generator, which
+    /// becomes the default slot.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let json = match std::fs::read_to_string(path.as_ref()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(RngSlots::default()),
+            Err(e) => return Err(e.into()),
+        };
+        if let Ok(slots) = serde_json::from_str::<RngSlots>(&json) {
+            return Ok(slots);
+        }
+        match serde_json::from_str::<SggPcg>(&json) {
+            Ok(rng) => {
+                let mut slots = RngSlots::default();
+                slots.slots.insert(DEFAULT_SLOT.to_string(), Slot { rng: Some(rng), history: Vec::new() });
+                Ok(slots)
+            }
+            Err(e) => Err(Error::from(format!(
+                "Failed to parse RNG state file {}: {}",
+                path.as_ref().display(),
+                e
+            ))),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::from(format!("Failed to serialize RNG state: {}", e)))?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    pub fn get(&self, slot: &str) -> Option<&SggPcg> {
+        self.slots.get(slot).and_then(|s| s.rng.as_ref())
+    }
+
+    /// Replace the state of `slot`, recording `operation` in its history.
+    pub fn update(&mut self, slot: &str, operation: String, rng: SggPcg) {
+        self.apply(slot, operation, Some(rng), None);
+    }
+
+    /// Revert the last operation on `slot` not undone yet, returning it,
+    /// and record the undo in the history. Undoing the operation that
+    /// created the slot leaves it empty.
+    pub fn undo(&mut self, slot: &str) -> Option<HistoryEntry> {
+        let history = &self.slots.get(slot)?.history;
+        let undone: Vec<usize> = history.iter().filter_map(|entry| entry.undoes).collect();
+        let index = (0..history.len())
+            .rev()
+            .find(|&i| history[i].undoes.is_none() && !undone.contains(&i))?;
+        let entry = history[index].clone();
+        self.apply(slot, format!("undo {}", entry.operation), entry.before.clone(), Some(index));
+        Some(entry)
+    }
+
+    fn apply(&mut self, slot: &str, operation: String, rng: Option<SggPcg>, undoes: Option<usize>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let entry = self.slots.entry(slot.to_string()).or_insert(Slot { rng: None, history: Vec::new() });
+        let before = std::mem::replace(&mut entry.rng, rng);
+        entry.history.push(HistoryEntry { operation, time, before, undoes });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undo_restores_previous_state() {
+        let mut slots = RngSlots::default();
+        slots.update("fresh", "set-seed 5".to_string(), SggPcg::new(5));
+        let mut advanced = SggPcg::new(5);
+        advanced.advance(10);
+        slots.update("fresh", "advance 10".to_string(), advanced.clone());
+        slots.update("other", "set-seed 7".to_string(), SggPcg::new(7));
+
+        assert_eq!(slots.get("fresh"), Some(&advanced));
+        assert_eq!(slots.undo("fresh").unwrap().operation, "advance 10");
+        assert_eq!(slots.get("fresh"), Some(&SggPcg::new(5)));
+        assert_eq!(slots.get("other"), Some(&SggPcg::new(7)));
+
+        slots.undo("fresh");
+        assert_eq!(slots.get("fresh"), None);
+        assert!(slots.undo("fresh").is_none());
+        let operations: Vec<&str> = slots.slots["fresh"].history.iter().map(|entry| entry.operation.as_str()).collect();
+        assert_eq!(operations, ["set-seed 5", "advance 10", "undo advance 10", "undo set-seed 5"]);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::error::Error;
+use mlua::{Lua, Value};
+use rand_core::{impls, RngCore};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of Lua frames recorded with every draw.
+pub const TRACE_STACK_DEPTH: usize = 4;
+
+/// Number of Lua frames recorded with every draw when bisecting a desync.
+pub const BISECT_STACK_DEPTH: usize = 12;
+
+/// Message of the error stopping a script once a [`RngTrace::window`] is
+/// complete.
+pub const WINDOW_COMPLETE: &str = "RNG trace window complete";
+
+/// One line of the trace file.
+#[derive(Clone, Debug, Serialize)]
+pub struct TraceRecord {
+    pub call: &'static str,
+    pub stream: i32,
+    /// Number of raw draws taken from the stream since it was last seeded.
+    pub offset: i64,
+    pub args: Vec<serde_json::Value>,
+    pub raw: Vec<u32>,
+    pub result: serde_json::Value,
+    pub stack: Vec<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct CallCounts {
+    pub calls: u64,
+    pub draws: u64,
+}
+
+/// What a desync bisection compares of each draw, small enough to keep
+/// for the millions of draws of a route search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Draw {
+    pub call: &'static str,
+    pub stream: i32,
+    pub offset: i64,
+    /// The result, or the seed for `randomseed`; `None` when it isn't a
+    /// number, such as a nil seed, so such draws still compare equal.
+    pub value: Option<f64>,
+}
+
+impl TraceRecord {
+    /// The parts of the record that identify the draw, regardless of where
+    /// in the scripts it was made.
+    pub fn draw(&self) -> Draw {
+        let value = match self.call {
+            "randomseed" => &self.args[0],
+            _ => &self.result,
+        };
+        Draw { call: self.call, stream: self.stream, offset: self.offset, value: value.as_f64() }
+    }
+}
+
+enum TraceSink {
+    File(BufWriter<File>),
+    Draws(Vec<Draw>),
+    /// Full records of draws `from..=to`; later draws stop the script.
+    Window { from: usize, to: usize, seen: usize, records: Vec<TraceRecord> },
+}
+
+/// Records every RNG hook call made by the scripts, either to a jsonl file
+/// or in memory, and optionally aggregates the calls by the Lua function
+/// that made them.
+pub struct RngTrace {
+    sink: TraceSink,
+    stack_depth: usize,
+    counts: Option<(PathBuf, BTreeMap<String, CallCounts>)>,
+}
+
+impl RngTrace {
+    pub fn create<P: AsRef<Path>>(path: P, counts_path: Option<PathBuf>) -> Result<Self, Error> {
+        Ok(RngTrace {
+            sink: TraceSink::File(BufWriter::new(File::create(path)?)),
+            stack_depth: TRACE_STACK_DEPTH,
+            counts: counts_path.map(|p| (p, BTreeMap::new())),
+        })
+    }
+
+    /// Keep only the [`Draw`] of every call, without stacks.
+    pub fn draws() -> Self {
+        RngTrace {
+            sink: TraceSink::Draws(Vec::new()),
+            stack_depth: 0,
+            counts: None,
+        }
+    }
+
+    /// Keep full records of draws `from..=to` (counting from 0), and stop
+    /// the script with [`WINDOW_COMPLETE`] at the next one.
+    pub fn window(from: usize, to: usize, stack_depth: usize) -> Self {
+        RngTrace {
+            sink: TraceSink::Window { from, to, seen: 0, records: Vec::new() },
+            stack_depth,
+            counts: None,
+        }
+    }
+
+    /// The draws kept by [`RngTrace::draws`].
+    pub fn draw_list(&self) -> &[Draw] {
+        match &self.sink {
+            TraceSink::Draws(draws) => draws,
+            _ => &[],
+        }
+    }
+
+    /// The records kept by [`RngTrace::window`], from its first draw on.
+    pub fn records(&self) -> &[TraceRecord] {
+        match &self.sink {
+            TraceSink::Window { records, .. } => records,
+            _ => &[],
+        }
+    }
+
+    pub fn seed(&mut self, lua: &Lua, stream: i32, seed: i32) -> Result<(), Error> {
+        self.draw(lua, TraceRecord {
+            call: "randomseed",
+            stream,
+            offset: 0,
+            args: vec![seed.into(), stream.into()],
+            raw: Vec::new(),
+            result: serde_json::Value::Null,
+            stack: Vec::new(),
+        })
+    }
+
+    /// Write `record`, filling in its stack from the current Lua call stack.
+    pub fn draw(&mut self, lua: &Lua, mut record: TraceRecord) -> Result<(), Error> {
+        if let Some((_, counts)) = &mut self.counts {
+            let entry = counts.entry(lua_caller(lua)).or_default();
+            entry.calls += 1;
+            entry.draws += record.raw.len() as u64;
+        }
+
+        match &mut self.sink {
+            TraceSink::File(writer) => {
+                record.stack = lua_stack(lua, self.stack_depth);
+                serde_json::to_writer(&mut *writer, &record)
+                    .map_err(|e| Error::from(format!("Failed to write RNG trace: {}", e)))?;
+                writer.write_all(b"\n")?;
+            }
+            TraceSink::Draws(draws) => draws.push(record.draw()),
+            TraceSink::Window { from, to, seen, records } => {
+                if *seen > *to {
+                    return Err(Error::from(WINDOW_COMPLETE.to_string()));
+                }
+                if *seen >= *from {
+                    record.stack = lua_stack(lua, self.stack_depth);
+                    records.push(record);
+                }
+                *seen += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the trace and write the aggregate counts, if requested.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if let TraceSink::File(writer) = &mut self.sink {
+            writer.flush()?;
+        }
+        if let Some((path, counts)) = &self.counts {
+            let json = serde_json::to_string_pretty(counts)
+                .map_err(|e| Error::from(format!("Failed to write RNG trace counts: {}", e)))?;
+            std::fs::write(path, json)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the first draw at which two traces differ, or `None` if they
+/// are identical. If one trace is a prefix of the other, the divergence is
+/// the first draw only the longer one made.
+pub fn first_divergence(a: &[Draw], b: &[Draw]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(i) => Some(i),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// The Lua frames from stack level `first_level` out, skipping C functions.
+pub(crate) fn lua_frames<'lua>(lua: &'lua Lua, first_level: usize) -> impl Iterator<Item = mlua::Debug<'lua>> {
+    (first_level..)
+        .map_while(move |level| lua.inspect_stack(level))
+        .filter(|debug| debug.source().what != "C")
+}
+
+/// Describe up to `depth` Lua frames of the caller of the current Rust
+/// function, innermost first, as `source:line in name`.
+pub fn lua_stack(lua: &Lua, depth: usize) -> Vec<String> {
+    // level 0 is the Rust function itself
+    lua_stack_from(lua, 1, depth)
+}
+
+/// Describe up to `depth` Lua frames from stack level `first_level` on;
+/// in a hook, level 0 is the function that was running.
+pub fn lua_stack_from(lua: &Lua, first_level: usize, depth: usize) -> Vec<String> {
+    lua_frames(lua, first_level)
+        .take(depth)
+        .map(|debug| {
+            let source = debug.source();
+            let file = source.short_src.as_deref().unwrap_or("?");
+            let name = debug.names().name.as_deref().unwrap_or(source.what).to_string();
+            format!("{}:{} in {}", file, debug.curr_line(), name)
+        })
+        .collect()
+}
+
+/// Identify the Lua function calling the current Rust function as
+/// `name (source:line_defined)`.
+pub fn lua_caller(lua: &Lua) -> String {
+    match lua_frames(lua, 1).next() {
+        Some(debug) => {
+            let source = debug.source();
+            let file = source.short_src.as_deref().unwrap_or("?");
+            let name = debug.names().name.as_deref().unwrap_or(source.what).to_string();
+            format!("{} ({}:{})", name, file, source.line_defined.unwrap_or(0))
+        }
+        None => "?".to_string(),
+    }
+}
+
+/// Convert a Lua argument to JSON for the trace; values that have no
+/// sensible JSON form are recorded by their type name.
+pub fn lua_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => (*b).into(),
+        Value::Integer(i) => (*i).into(),
+        Value::Number(n) => (*n).into(),
+        Value::String(s) => s.to_string_lossy().into_owned().into(),
+        other => other.type_name().into(),
+    }
+}
+
+/// Wraps a generator and remembers every raw output taken from it.
+pub struct RecordingRng<'a, R: RngCore> {
+    rng: &'a mut R,
+    pub raw: Vec<u32>,
+}
+
+impl<'a, R: RngCore> RecordingRng<'a, R> {
+    pub fn new(rng: &'a mut R) -> Self {
+        RecordingRng { rng, raw: Vec::new() }
+    }
+}
+
+impl<'a, R: RngCore> RngCore for RecordingRng<'a, R> {
+    fn next_u32(&mut self) -> u32 {
+        let value = self.rng.next_u32();
+        self.raw.push(value);
+        value
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(call: &'static str, result: u32, line: &str) -> Draw {
+        TraceRecord {
+            call,
+            stream: 0,
+            offset: 0,
+            args: Vec::new(),
+            raw: vec![result],
+            result: result.into(),
+            stack: vec![line.to_string()],
+        }
+        .draw()
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let a = vec![record("randomint", 1, "a:1"), record("random", 2, "a:2")];
+        let moved = vec![record("randomint", 1, "b:9"), record("random", 2, "b:10")];
+        assert_eq!(first_divergence(&a, &moved), None);
+
+        let changed = vec![record("randomint", 1, "a:1"), record("randomint", 2, "a:2")];
+        assert_eq!(first_divergence(&a, &changed), Some(1));
+        assert_eq!(first_divergence(&a, &a[..1]), Some(1));
+
+        // `randomseed()` without a seed has no numeric value to compare
+        let unseeded = |line: &str| {
+            TraceRecord {
+                call: "randomseed",
+                stream: 0,
+                offset: 0,
+                args: vec![serde_json::Value::Null],
+                raw: Vec::new(),
+                result: serde_json::Value::Null,
+                stack: vec![line.to_string()],
+            }
+            .draw()
+        };
+        assert_eq!(first_divergence(&[unseeded("a:1")], &[unseeded("b:1")]), None);
+        assert_eq!(first_divergence(&[unseeded("a:1"), a[0]], &[unseeded("b:1"), a[1]]), Some(1));
+    }
+}
@@ -1,4 +1,7 @@
+use crate::config::Profile;
+use crate::gui::{DEFAULT_SAVE_FILE, DEFAULT_SCRIPTS_DIR};
 use druid::{Data, Lens};
+use std::sync::Arc;
 
 #[derive(Clone, Data, Lens)]
 pub struct AppState {
@@ -19,10 +22,13 @@ pub struct AppState {
     pub scripts_dir_path: String,
     pub script_file: String,
     pub found_seed: Option<i32>,
+    /// The config profile, passed on to every routefinder run
+    pub profile: Arc<Profile>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
+        let config = Profile::discover_or_default();
         Self {
             assault: String::new(),
             grasp: "1".to_string(), // Prefilled, uneditable 
@@ -37,10 +43,11 @@ impl Default for AppState {
             flourish: String::new(),
             defiance: "1".to_string(), // Prefilled, uneditable
             text_output: "Enter percentage values for each field.\n".to_string(),
-            save_file_path: "FreshFile.sav".to_string(),
-            scripts_dir_path: "~/workspace/hades/routefinder/assets/Scripts/".to_string(),
-            script_file: "SackRouteIncrementally.lua".to_string(),
+            save_file_path: config.save_file.as_ref().map_or_else(|| DEFAULT_SAVE_FILE.to_string(), |path| path.display().to_string()),
+            scripts_dir_path: config.scripts_dir.as_ref().map_or_else(|| DEFAULT_SCRIPTS_DIR.to_string(), |path| path.display().to_string()),
+            script_file: config.script.as_ref().map_or_else(|| "SackRouteIncrementally.lua".to_string(), |path| path.display().to_string()),
             found_seed: None,
+            profile: Arc::new(config),
         }
     }
 }
@@ -0,0 +1,280 @@
+use crate::error::Error;
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue, Table, Value};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// `os` functions kept in the sandbox; they only read the clock.
+const OS_KEEP: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+/// `io` functions kept as they are; `open` and `lines` are checked instead.
+const IO_KEEP: [&str; 6] = ["close", "read", "stderr", "stdout", "type", "write"];
+
+/// `debug` functions kept; the rest can reach into other functions' locals
+/// and the registry.
+const DEBUG_KEEP: [&str; 2] = ["getinfo", "traceback"];
+
+/// What a script wants to do with a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Restrictions for running untrusted scripts: no processes, environment,
+/// native modules, binary chunks or debug hooks, files read only inside
+/// the readable directories and written only inside the writable ones.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    read_dirs: Vec<PathBuf>,
+    write_dirs: Vec<PathBuf>,
+    denied_dirs: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Allow reading files inside `read_dirs` and reading and writing them
+    /// inside `write_dirs`. The directories must exist.
+    pub fn new<R, W, P, Q>(read_dirs: R, write_dirs: W) -> Result<Sandbox, Error>
+    where
+        R: IntoIterator<Item = P>,
+        W: IntoIterator<Item = Q>,
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let canonicalize = |dir: &Path| {
+            dir.canonicalize()
+                .map_err(|e| Error::from(format!("Can't allow sandbox directory {}: {}", dir.display(), e)))
+        };
+        let write_dirs: Vec<PathBuf> =
+            write_dirs.into_iter().map(|dir| canonicalize(dir.as_ref())).collect::<Result<_, _>>()?;
+        let mut read_dirs: Vec<PathBuf> =
+            read_dirs.into_iter().map(|dir| canonicalize(dir.as_ref())).collect::<Result<_, _>>()?;
+        read_dirs.extend(write_dirs.iter().cloned());
+        Ok(Sandbox { read_dirs, write_dirs, denied_dirs: Vec::new() })
+    }
+
+    /// Refuse all access inside `dir`, even where it's in an allowed
+    /// directory. It needn't exist yet.
+    pub fn deny(&mut self, dir: &Path) {
+        if let Some(dir) = resolve(dir) {
+            self.denied_dirs.push(dir);
+        }
+    }
+
+    /// Resolve `path` and check that it's inside a directory allowing
+    /// `access`. The file itself needn't exist, so that it can be created.
+    pub fn check(&self, path: &Path, access: Access) -> Result<PathBuf, String> {
+        let denied = || format!("Sandbox: access to '{}' is not allowed", path.display());
+        let resolved = resolve(path).ok_or_else(denied)?;
+        let inside = |dirs: &[PathBuf]| dirs.iter().any(|dir| resolved.starts_with(dir));
+        if inside(&self.denied_dirs) {
+            Err(denied())
+        } else if inside(if access == Access::Read { &self.read_dirs } else { &self.write_dirs }) {
+            Ok(resolved)
+        } else if inside(&self.read_dirs) {
+            Err(format!("Sandbox: '{}' is read-only, allow writing with --allow-dir", path.display()))
+        } else {
+            Err(denied())
+        }
+    }
+
+    /// Replace the dangerous parts of the standard library in `lua`. What
+    /// the game scripts use (`os.time`, `io.write`, `require` of Lua files
+    /// in allowed directories, ...) keeps working.
+    pub fn install(self: &Rc<Self>, lua: &Lua) -> mlua::Result<()> {
+        let globals = lua.globals();
+        let package: Table = globals.get("package")?;
+        let loaded: Table = package.get("loaded")?;
+        // `require` hands out `package.loaded`, so it has to see the same tables
+        let replace = |name: &str, table: Table| -> mlua::Result<()> {
+            loaded.set(name, table.clone())?;
+            globals.set(name, table)
+        };
+        replace("os", keep(lua, &globals.get("os")?, &OS_KEEP)?)?;
+        replace("debug", keep(lua, &globals.get("debug")?, &DEBUG_KEEP)?)?;
+
+        let io: Table = globals.get("io")?;
+        let sandboxed_io = keep(lua, &io, &IO_KEEP)?;
+        sandboxed_io.set("open", self.checked(lua, io.get("open")?, false)?)?;
+        sandboxed_io.set("lines", self.checked(lua, io.get("lines")?, true)?)?;
+        replace("io", sandboxed_io)?;
+
+        let loadfile = Rc::new(lua.create_registry_value(globals.get::<_, Function>("loadfile")?)?);
+        let text_loadfile = loadfile.clone();
+        let loadfile_text = lua.create_function(move |lua, (path, _mode, env): (Value, Value, Value)| {
+            lua.registry_value::<Function>(&text_loadfile)?.call::<_, MultiValue>((path, "t", env))
+        })?;
+        globals.set("loadfile", self.checked(lua, loadfile_text, false)?)?;
+        let sandbox = self.clone();
+        let dofile_loadfile = loadfile.clone();
+        let dofile = lua.create_function(move |lua, path: String| {
+            sandbox.check(Path::new(&path), Access::Read).map_err(mlua::Error::runtime)?;
+            load_text(lua, &dofile_loadfile, &path)?.call::<_, MultiValue>(())
+        })?;
+        globals.set("dofile", dofile)?;
+        let load = lua.create_registry_value(globals.get::<_, Function>("load")?)?;
+        let load_text_only = lua.create_function(move |lua, (chunk, name, _mode, env): (Value, Value, Value, Value)| {
+            lua.registry_value::<Function>(&load)?.call::<_, MultiValue>((chunk, name, "t", env))
+        })?;
+        globals.set("loadstring", load_text_only.clone())?;
+        globals.set("load", load_text_only)?;
+
+        // only Lua source files found on `package.path` inside readable directories
+        let searchers: Table = package.get("searchers")?;
+        let sandbox = self.clone();
+        let lua_searcher = lua.create_function(move |lua, name: String| {
+            let path: String = lua.globals().get::<_, Table>("package")?.get("path")?;
+            match sandbox.search(&name, &path) {
+                Ok(file) => {
+                    let file = file.to_string_lossy().into_owned();
+                    let loader = load_text(lua, &loadfile, &file)?;
+                    (Value::Function(loader), file).into_lua_multi(lua)
+                }
+                Err(tried) => tried.into_lua_multi(lua),
+            }
+        })?;
+        let sandbox = self.clone();
+        let searchpath = lua.create_function(move |lua, (name, path): (String, String)| {
+            match sandbox.search(&name, &path) {
+                Ok(file) => file.to_string_lossy().into_owned().into_lua_multi(lua),
+                Err(tried) => (Value::Nil, tried).into_lua_multi(lua),
+            }
+        })?;
+        let lua_searchers = lua.create_sequence_from([searchers.get::<_, Value>(1)?, Value::Function(lua_searcher)])?;
+        package.set("searchers", lua_searchers)?;
+        package.set("searchpath", searchpath)?;
+        package.set("loadlib", Value::Nil)?;
+        package.set("cpath", "")?;
+        let path: Vec<String> =
+            self.read_dirs.iter().map(|dir| dir.join("?.lua").to_string_lossy().into_owned()).collect();
+        package.set("path", path.join(";"))?;
+        Ok(())
+    }
+
+    /// The first readable file for module `name` on the `;`-separated
+    /// `path` templates, or the files tried in `require`'s format.
+    fn search(&self, name: &str, path: &str) -> Result<PathBuf, String> {
+        let name = name.replace('.', std::path::MAIN_SEPARATOR_STR);
+        let mut tried = String::new();
+        for template in path.split(';').filter(|template| !template.is_empty()) {
+            let file = PathBuf::from(template.replace('?', &name));
+            if self.check(&file, Access::Read).is_ok() && file.is_file() {
+                return Ok(file);
+            }
+            tried += &format!("\n\tno file '{}'", file.display());
+        }
+        Err(tried)
+    }
+
+    /// Wrap `function` so that its first argument, a path, is checked.
+    /// With `optional`, calling it without a path is allowed too. Opening
+    /// with a mode that writes needs write access.
+    fn checked<'lua>(self: &Rc<Self>, lua: &'lua Lua, function: Function<'lua>, optional: bool) -> mlua::Result<Function<'lua>> {
+        let sandbox = self.clone();
+        let function = lua.create_registry_value(function)?;
+        lua.create_function(move |lua, args: MultiValue| {
+            let mut iter = args.iter();
+            match iter.next() {
+                Some(Value::String(path)) => {
+                    let access = match iter.next() {
+                        Some(Value::String(mode)) if mode.as_bytes().iter().any(|c| b"wa+".contains(c)) => {
+                            Access::Write
+                        }
+                        _ => Access::Read,
+                    };
+                    sandbox.check(Path::new(path.to_str()?), access).map_err(mlua::Error::runtime)?;
+                }
+                None | Some(Value::Nil) if optional => {}
+                _ => return Err(mlua::Error::runtime("Sandbox: expected a file path")),
+            }
+            lua.registry_value::<Function>(&function)?.call::<_, MultiValue>(args)
+        })
+    }
+}
+
+/// Compile the Lua source file at `path` with the original `loadfile`,
+/// refusing binary chunks.
+fn load_text<'lua>(lua: &'lua Lua, loadfile: &mlua::RegistryKey, path: &str) -> mlua::Result<Function<'lua>> {
+    let (function, error): (Option<Function>, Option<String>) =
+        lua.registry_value::<Function>(loadfile)?.call((path, "t"))?;
+    function.ok_or_else(|| mlua::Error::runtime(error.unwrap_or_default()))
+}
+
+/// The canonical form of `path`, through its parent if it doesn't exist.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    match path.canonicalize() {
+        Ok(resolved) => Some(resolved),
+        Err(_) => {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Some(parent.canonicalize().ok()?.join(path.file_name()?))
+        }
+    }
+}
+
+/// A copy of `table` with only the entries named in `names`.
+fn keep<'lua>(lua: &'lua Lua, table: &Table<'lua>, names: &[&str]) -> mlua::Result<Table<'lua>> {
+    let kept = lua.create_table()?;
+    for name in names {
+        kept.set(*name, table.get::<_, Value>(*name)?)?;
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_sandbox_limits_files_and_libraries() {
+        let dir = TempDir::new("sandbox");
+        let (writable, read_only) = (dir.join("Writable"), dir.join("ReadOnly"));
+        std::fs::create_dir_all(&writable).unwrap();
+        std::fs::create_dir_all(&read_only).unwrap();
+        std::fs::write(writable.join("Allowed.lua"), "return 42").unwrap();
+        std::fs::write(read_only.join("Module.lua"), "return 'module'").unwrap();
+        // the simulator's VM can load binary chunks, so the sandbox has to stop them
+        let lua = unsafe { Lua::unsafe_new() };
+        let dump = lua.load("return 7").into_function().unwrap().dump(false);
+        std::fs::write(read_only.join("Binary.lua"), dump).unwrap();
+        std::fs::create_dir_all(writable.join("Cache")).unwrap();
+        let mut sandbox = Sandbox::new([&read_only], [&writable]).unwrap();
+        sandbox.deny(&writable.join("Cache"));
+        let sandbox = Rc::new(sandbox);
+        sandbox.install(&lua).unwrap();
+        lua.globals().set("Dir", dir.path().to_string_lossy().into_owned()).unwrap();
+
+        let allowed: (i64, i64, bool, String) = lua
+            .load(
+                "return dofile(Dir .. '/Writable/Allowed.lua'), \
+                 io.open(Dir .. '/Writable/New.txt', 'w'):write('x') and 1, os.time() > 0, require('Module')",
+            )
+            .eval()
+            .unwrap();
+        let denied = [
+            "io.open('/etc/hostname')",
+            "dofile(Dir .. '/x.lua')",
+            "os.execute('true')",
+            "io.popen('ls')",
+            "io.open(Dir .. '/ReadOnly/New.txt', 'w')",
+            "io.open(Dir .. '/ReadOnly/Module.lua', 'r+')",
+            "dofile(Dir .. '/ReadOnly/Binary.lua')",
+            "require('Binary')",
+            "io.open(Dir .. '/Writable/Cache/Chunk.luac', 'w')",
+            "package.path = '/etc/?' require('hostname')",
+        ]
+        .iter()
+        .filter(|code| lua.load(**code).exec().is_err())
+        .count();
+        let removed: (Value, Value, Value) =
+            lua.load("return require('os').execute, package.loaded.io.popen, require('debug').sethook").eval().unwrap();
+        let binary = lua.load("return load(string.dump(function() end))").eval::<MultiValue>().unwrap();
+
+        assert_eq!(allowed, (42, 1, true, "module".to_string()));
+        assert_eq!(denied, 10);
+        assert_eq!(removed, (Value::Nil, Value::Nil, Value::Nil));
+        assert!(matches!(binary.iter().next(), Some(Value::Nil)));
+    }
+}
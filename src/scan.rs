@@ -0,0 +1,146 @@
+use crate::error::Error;
+use crate::simulator::{CapturedOutput, SimulatorBuilder};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Values to scan, written `A..B` (excluding B) or `A..=B`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanRange {
+    pub start: i64,
+    /// Exclusive.
+    pub end: i64,
+}
+
+impl FromStr for ScanRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let number = |part: &str| {
+            part.trim()
+                .parse::<i64>()
+                .map_err(|_| Error::from(format!("Invalid number '{}' in range '{}'", part, s)))
+        };
+        if let Some((start, end)) = s.split_once("..=") {
+            Ok(ScanRange { start: number(start)?, end: number(end)?.saturating_add(1) })
+        } else if let Some((start, end)) = s.split_once("..") {
+            Ok(ScanRange { start: number(start)?, end: number(end)? })
+        } else {
+            Err(Error::from(format!("Invalid range '{}': expected A..B or A..=B", s)))
+        }
+    }
+}
+
+pub struct ScanConfig<B> {
+    pub script: PathBuf,
+    /// Global the scanned value is assigned to before each run of the script.
+    pub variable: String,
+    pub range: ScanRange,
+    /// Number of Lua VMs, or 0 for one per CPU.
+    pub jobs: usize,
+    /// Configures the simulation of each VM; its output is replaced to
+    /// collect what the script prints and emits.
+    pub builder: B,
+}
+
+/// One line printed by the script for one scanned value, or one record it
+/// emitted, as its name and JSON data separated by a tab.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanRow {
+    pub value: i64,
+    pub line: String,
+}
+
+/// Run `config.script` once for every value in the range, spread over
+/// `config.jobs` Lua VMs. Each VM loads the game scripts and save once,
+/// and restores that state before every value. Returns what the script
+/// printed, sorted by value.
+pub fn scan<B>(config: &ScanConfig<B>) -> Result<Vec<ScanRow>, Error>
+where
+    B: Fn() -> SimulatorBuilder + Sync,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.jobs)
+        .build()
+        .map_err(|e| Error::from(format!("Failed to start scan workers: {}", e)))?;
+    let next = AtomicI64::new(config.range.start);
+
+    let mut rows = Vec::new();
+    for worker_rows in pool.broadcast(|_| scan_worker(config, &next)) {
+        rows.extend(worker_rows?);
+    }
+    // each value is scanned by a single worker, so a stable sort keeps its lines in order
+    rows.sort_by_key(|row| row.value);
+    Ok(rows)
+}
+
+fn scan_worker<B>(config: &ScanConfig<B>, next: &AtomicI64) -> Result<Vec<ScanRow>, Error>
+where
+    B: Fn() -> SimulatorBuilder,
+{
+    let output = CapturedOutput::default();
+    let mut emitted = output.clone();
+    let sim = (config.builder)()
+        .output(output.clone())
+        .on_emit(move |record| {
+            let _ = writeln!(emitted, "{}\t{}", record.name, record.data);
+        })
+        .build()?;
+    let script = sim.load_file(&config.script)?;
+    let snapshot = sim.snapshot()?;
+
+    let mut rows = Vec::new();
+    loop {
+        let value = next.fetch_add(1, Ordering::Relaxed);
+        if value >= config.range.end {
+            break;
+        }
+        sim.restore(&snapshot)?;
+        sim.set(&config.variable, value)?;
+        let result = script.call::<_, ()>(());
+        rows.extend(output.take().lines().map(|line| ScanRow { value, line: line.to_string() }));
+        if let Err(err) = result {
+            rows.push(ScanRow { value, line: format!("Error: {}", err) });
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{rand_int, SggPcg};
+    use crate::simulator::Simulator;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_scan_collects_sorted_output() {
+        let dir = TempDir::new("scan");
+        let script = dir.join("scan.lua");
+        std::fs::write(
+            &script,
+            "randomseed(Seed)\nif Seed % 3 == 0 then print(randomint(1, 100)) Emit('Seed', { Seed }) end\n",
+        )
+        .unwrap();
+        let config = ScanConfig {
+            script: script.clone(),
+            variable: "Seed".to_string(),
+            range: "1..=30".parse().unwrap(),
+            jobs: 3,
+            builder: Simulator::builder,
+        };
+        let rows = scan(&config).unwrap();
+
+        let expected: Vec<ScanRow> = (1..=30)
+            .filter(|seed| seed % 3 == 0)
+            .flat_map(|seed| {
+                vec![
+                    ScanRow { value: seed, line: rand_int(&mut SggPcg::new(seed as u64), 1, 100).to_string() },
+                    ScanRow { value: seed, line: format!("Seed\t[{}]", seed) },
+                ]
+            })
+            .collect();
+        assert_eq!(rows, expected);
+    }
+}
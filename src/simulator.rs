@@ -0,0 +1,1188 @@
+use crate::cache::StartupCache;
+use crate::emit::{self, EmitRecord};
+use crate::engine_calls::{self, EngineCall, EngineRecorder, EngineStub, MissingGlobal};
+use crate::error::Error;
+use crate::limits::{self, Limit, LimitExceeded, LimitState, Limits};
+use crate::profiler::{Profile, Profiler};
+use crate::lua_rng::{self, LuaRng};
+use crate::lua_vars::LuaVar;
+use crate::luabins;
+use crate::mods::{self, ModImports};
+use crate::rng::{rand_double, rand_int, GameRng, RngStreams, SggPcg};
+use crate::sandbox::{Access, Sandbox};
+use crate::rng_trace::{lua_frames, lua_to_json, RecordingRng, RngTrace, TraceRecord};
+use crate::save::{self, UncompressedSize};
+use mlua::{
+    FromLua, FromLuaMulti, Function, HookTriggers, IntoLua, LightUserData, Lua, LuaOptions, RegistryKey, Table, Value,
+    Variadic,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::c_void;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// This repository's `Utils` helpers, which `Import` searches right after
+/// the importing script's directory unless [`SimulatorBuilder::utils_dir`]
+/// names another directory. Fixed at build time, so it doesn't depend on
+/// where routefinder is run from.
+pub const DEFAULT_UTILS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/Utils");
+
+/// A Lua VM with the engine hooks installed, the game scripts loaded and
+/// the save file's globals applied, ready to run route scripts:
+///
+/// ```no_run
+/// # use routefinder::simulator::Simulator;
+/// let sim = Simulator::builder()
+///     .save_file("FreshFile.sav")
+///     .scripts_dir("Scripts")
+///     .lua_var("Seed=1234".parse()?)
+///     .build()?;
+/// sim.run_file("FreshFilePredict.lua")?;
+/// let seed: i64 = sim.get("Seed")?;
+/// # Ok::<(), routefinder::error::Error>(())
+/// ```
+pub struct Simulator<R: GameRng + 'static = SggPcg> {
+    lua: Lua,
+    streams: Rc<RefCell<RngStreams<R>>>,
+    trace: Rc<RefCell<Option<RngTrace>>>,
+    importer: Importer,
+    engine: Option<Rc<RefCell<EngineRecorder>>>,
+    limits: Option<Rc<LimitState>>,
+    profiler: Option<Rc<Profiler>>,
+    emitted: Rc<RefCell<EmitSink>>,
+    /// The full `debug` library, which snapshots need even when sandboxed.
+    debug: RegistryKey,
+}
+
+/// What loading files and `Import` need besides the importing file's
+/// directory.
+#[derive(Clone, Default)]
+struct Importer {
+    cache: Option<StartupCache>,
+    mods: Rc<ModImports>,
+    sandbox: Option<Rc<Sandbox>>,
+    /// Directories searched after the loaded file's own.
+    search_path: Rc<Vec<PathBuf>>,
+    /// Files imported so far, which `Import` doesn't run again.
+    imported: Rc<RefCell<HashSet<PathBuf>>>,
+}
+
+/// Where `Emit` records go: kept for `take_emitted`, or handed to a callback.
+enum EmitSink {
+    Collect(Vec<EmitRecord>),
+    Callback(Box<dyn FnMut(EmitRecord)>),
+}
+
+/// The globals and RNG streams of a simulation at some point, to return
+/// to with [`Simulator::restore`]. Tables are copied, along with the
+/// upvalues of the Lua functions reachable from the globals, so file-level
+/// locals roll back too; functions, userdata and the standard libraries
+/// are shared. State kept only in the registry or in functions nothing
+/// global refers to isn't captured.
+pub struct Snapshot<R: GameRng = SggPcg> {
+    globals: RegistryKey,
+    /// `{ function, index, value }` for each upvalue, once per variable.
+    upvalues: RegistryKey,
+    streams: RngStreams<R>,
+    imported: HashSet<PathBuf>,
+}
+
+/// An output sink that keeps what the scripts print, for reading back
+/// through a clone of it.
+#[derive(Clone, Default)]
+pub struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl CapturedOutput {
+    /// Everything printed since the last call.
+    pub fn take(&self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut *self.0.borrow_mut())).into_owned()
+    }
+}
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SimulatorBuilder<R: GameRng + 'static = SggPcg> {
+    save_file: Option<PathBuf>,
+    scripts_dir: Option<PathBuf>,
+    mods_dir: Option<PathBuf>,
+    lua_vars: Vec<LuaVar>,
+    output: Option<Box<dyn Write>>,
+    on_emit: Option<Box<dyn FnMut(EmitRecord)>>,
+    rng_trace: Option<RngTrace>,
+    cache_dir: Option<PathBuf>,
+    sandbox_dirs: Option<Vec<PathBuf>>,
+    sandbox_write_dirs: Vec<PathBuf>,
+    utils_dir: Option<PathBuf>,
+    lib_dirs: Vec<PathBuf>,
+    strict: bool,
+    record_engine: bool,
+    engine_stubs: Vec<EngineStub>,
+    limits: Limits,
+    profile: bool,
+    rng: PhantomData<R>,
+}
+
+impl Simulator<SggPcg> {
+    pub fn builder() -> SimulatorBuilder<SggPcg> {
+        SimulatorBuilder::new()
+    }
+}
+
+impl<R: GameRng + 'static> Default for SimulatorBuilder<R> {
+    fn default() -> Self {
+        SimulatorBuilder::new()
+    }
+}
+
+impl<R: GameRng + 'static> SimulatorBuilder<R> {
+    pub fn new() -> Self {
+        SimulatorBuilder {
+            save_file: None,
+            scripts_dir: None,
+            mods_dir: None,
+            lua_vars: Vec::new(),
+            output: None,
+            on_emit: None,
+            rng_trace: None,
+            cache_dir: None,
+            sandbox_dirs: None,
+            sandbox_write_dirs: Vec::new(),
+            utils_dir: None,
+            lib_dirs: Vec::new(),
+            strict: false,
+            record_engine: false,
+            engine_stubs: Vec::new(),
+            limits: Limits::default(),
+            profile: false,
+            rng: PhantomData,
+        }
+    }
+
+    /// Save file whose globals the simulation starts from.
+    pub fn save_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.save_file = Some(path.into());
+        self
+    }
+
+    /// Hades `Scripts` directory holding `Main.lua` and `RoomManager.lua`.
+    pub fn scripts_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.scripts_dir = Some(path.into());
+        self
+    }
+
+    /// Directory of mods to import into the game scripts as the mod
+    /// importer would, following each mod's `modfile.txt`.
+    pub fn mods_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.mods_dir = Some(path.into());
+        self
+    }
+
+    /// Set a global after the save is applied. Later variables win.
+    pub fn lua_var(mut self, var: LuaVar) -> Self {
+        self.lua_vars.push(var);
+        self
+    }
+
+    pub fn lua_vars<I: IntoIterator<Item = LuaVar>>(mut self, vars: I) -> Self {
+        self.lua_vars.extend(vars);
+        self
+    }
+
+    /// Send everything the scripts `print` to `output` instead of stdout.
+    pub fn output<W: Write + 'static>(mut self, output: W) -> Self {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// Hand every record the scripts `Emit` to `on_emit` as it's made,
+    /// instead of keeping them for [`Simulator::take_emitted`].
+    pub fn on_emit<F: FnMut(EmitRecord) + 'static>(mut self, on_emit: F) -> Self {
+        self.on_emit = Some(Box::new(on_emit));
+        self
+    }
+
+    /// Record every RNG hook call into `trace`.
+    pub fn rng_trace(mut self, trace: RngTrace) -> Self {
+        self.rng_trace = Some(trace);
+        self
+    }
+
+    /// Cache compiled Lua files and the decoded save in `dir`.
+    pub fn cache_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Run the scripts in a [`Sandbox`], with files read only from the
+    /// scripts and mods directories and those added with `allow_read_dir`,
+    /// and written only in those added with `allow_dir`.
+    pub fn sandbox(mut self) -> Self {
+        self.sandbox_dirs.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Let sandboxed scripts read files in `dir`.
+    pub fn allow_read_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.sandbox_dirs.get_or_insert_with(Vec::new).push(dir.into());
+        self
+    }
+
+    /// Let sandboxed scripts read and write files in `dir`.
+    pub fn allow_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.sandbox_dirs.get_or_insert_with(Vec::new);
+        self.sandbox_write_dirs.push(dir.into());
+        self
+    }
+
+    /// Search `dir` for `Import`s right after the importing script's
+    /// directory, instead of [`DEFAULT_UTILS_DIR`].
+    pub fn utils_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.utils_dir = Some(dir.into());
+        self
+    }
+
+    /// Search `dir` for files to `Import`, after the importing script's
+    /// directory, `Utils` and the scripts directory.
+    pub fn lib_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.lib_dirs.push(dir.into());
+        self
+    }
+
+    /// Make reading an undefined global an error, except in the game's
+    /// own scripts, which rely on reading them as nil.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Record the scripts' calls to the engine functions `Engine.lua`
+    /// stubs, and the undefined globals the game scripts read.
+    pub fn record_engine_calls(mut self) -> Self {
+        self.record_engine = true;
+        self
+    }
+
+    /// Define an engine function that records its calls and returns a
+    /// fixed value, replacing any stub from `Engine.lua`.
+    pub fn engine_stub(mut self, stub: EngineStub) -> Self {
+        self.record_engine = true;
+        self.engine_stubs.push(stub);
+        self
+    }
+
+    /// Stop scripts that run longer than `timeout`, counted from `build`
+    /// or the last [`Simulator::reset_limits`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.limits.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop scripts after about `count` VM instructions, counted like the
+    /// timeout; see [`limits::CHECK_INTERVAL`].
+    pub fn max_instructions(mut self, count: u64) -> Self {
+        self.limits.max_instructions = Some(count);
+        self
+    }
+
+    /// Stop scripts once the VM, game scripts and save included, uses
+    /// more than `bytes` of memory.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.limits.max_memory = Some(bytes);
+        self
+    }
+
+    /// Sample the Lua stack while scripts run, for [`Simulator::take_profile`].
+    pub fn profile(mut self) -> Self {
+        self.profile = true;
+        self
+    }
+
+    /// Create the VM, install the hooks and load the game scripts and save.
+    /// Without a scripts directory or save file only the hooks are set up.
+    pub fn build(self) -> Result<Simulator<R>, Error> {
+        let mods = match &self.mods_dir {
+            Some(_) if self.scripts_dir.is_none() => {
+                return Err(Error::from("Mods need a scripts directory to be imported into".to_string()))
+            }
+            Some(dir) => ModImports::new(&mods::load_mods(dir)?),
+            None => ModImports::default(),
+        };
+        let utils_dir = self.utils_dir.unwrap_or_else(|| PathBuf::from(DEFAULT_UTILS_DIR));
+        let search_path: Vec<PathBuf> = (utils_dir.is_dir().then_some(utils_dir).into_iter())
+            .chain(self.scripts_dir.clone())
+            .chain(self.lib_dirs)
+            .collect();
+        let sandbox = match self.sandbox_dirs {
+            Some(dirs) => {
+                let game_dirs = self.mods_dir.iter().chain(&search_path);
+                let mut sandbox = Sandbox::new(dirs.iter().chain(game_dirs), &self.sandbox_write_dirs)?;
+                // the cached bytecode is loaded unchecked, so scripts mustn't plant any
+                if let Some(cache_dir) = &self.cache_dir {
+                    sandbox.deny(cache_dir);
+                }
+                Some(Rc::new(sandbox))
+            }
+            None => None,
+        };
+
+        let lua = unsafe { Lua::unsafe_new_with(mlua::StdLib::ALL, LuaOptions::new()) };
+        let debug = lua.create_registry_value(lua.globals().get::<_, Table>("debug")?)?;
+        let mut sim = Simulator {
+            lua,
+            streams: Rc::new(RefCell::new(RngStreams::<R>::new())),
+            trace: Rc::new(RefCell::new(self.rng_trace)),
+            importer: Importer {
+                cache: self.cache_dir.map(StartupCache::new),
+                mods: Rc::new(mods),
+                sandbox,
+                search_path: Rc::new(search_path),
+                imported: Rc::default(),
+            },
+            engine: self.record_engine.then(Rc::default),
+            limits: None,
+            profiler: None,
+            emitted: Rc::new(RefCell::new(match self.on_emit {
+                Some(on_emit) => EmitSink::Callback(on_emit),
+                None => EmitSink::Collect(Vec::new()),
+            })),
+            debug,
+        };
+
+        // Decode the save before running anything, so a bad path fails fast
+        let lua_state = match &self.save_file {
+            Some(path) => {
+                let save_file = read_file(path)
+                    .map_err(|e| Error::from(format!("Failed to read save file {}: {}", path.display(), e)))?;
+                let decode = || -> Result<Vec<u8>, Error> {
+                    let lua_state_lz4 = save::read(&mut save_file.as_slice(), "save".to_string())?.lua_state_lz4;
+                    Ok(lz4::block::decompress(
+                        lua_state_lz4.as_slice(),
+                        Some(save::HadesSaveV16::UNCOMPRESSED_SIZE),
+                    )?)
+                };
+                Some(match &sim.importer.cache {
+                    Some(cache) => cache.save_state(path, &save_file, decode)?,
+                    None => decode()?,
+                })
+            }
+            None => None,
+        };
+
+        if let Some(output) = self.output {
+            sim.install_output(output)?;
+        }
+        sim.install_hooks()?;
+        sim.importer.install(&sim.lua)?;
+        if let Some(recorder) = &sim.engine {
+            for stub in &self.engine_stubs {
+                engine_calls::record_calls(&sim.lua, recorder, &stub.name, Some(stub.returns.clone()))?;
+            }
+        }
+        let strict = Rc::new(Cell::new(false));
+        if self.strict || sim.engine.is_some() {
+            let mut game_sources = vec![chunk_name(Path::new("Engine.lua"))];
+            for dir in self.scripts_dir.iter().chain(&self.mods_dir) {
+                game_sources.push(format!("{}{}", chunk_name(dir), std::path::MAIN_SEPARATOR));
+            }
+            sim.install_global_index(game_sources, strict.clone())?;
+        }
+        if let Some(sandbox) = &sim.importer.sandbox {
+            sandbox.install(&sim.lua)?;
+        }
+
+        if let Some(scripts_dir) = &self.scripts_dir {
+            sim.run_game_file(scripts_dir, "Main.lua")?;
+            sim.run_game_file(scripts_dir, "RoomManager.lua")?;
+        }
+
+        if let Some(lua_state) = lua_state {
+            let save_data = luabins::load(&sim.lua, &mut lua_state.as_slice(), "luabins".to_string())?;
+            sim.lua.globals().set("RouteFinderSaveFileData", save_data)?;
+
+            // put save file data into globals
+            sim.lua
+                .load(
+                    r#"
+                    for _,savedValues in pairs(RouteFinderSaveFileData) do
+                        for key, value in pairs(savedValues) do
+                        if not SaveIgnores[key] then
+                            _G[key] = value
+                        end
+                        end
+                    end
+                    "#,
+                )
+                .exec()?;
+        }
+
+        for lua_var in &self.lua_vars {
+            lua_var.apply(&sim.lua)?;
+        }
+
+        strict.set(self.strict);
+        // loading the game isn't limited or profiled
+        if !self.limits.is_empty() {
+            sim.limits = Some(LimitState::new(&sim.lua, self.limits)?);
+        }
+        if self.profile {
+            sim.profiler = Some(Rc::new(Profiler::new()));
+        }
+        sim.install_instruction_hook();
+        Ok(sim)
+    }
+}
+
+impl<R: GameRng + 'static> Simulator<R> {
+    pub fn lua(&self) -> &Lua {
+        &self.lua
+    }
+
+    /// Load and run a Lua file; `Import` inside it resolves relative to the file.
+    pub fn run_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.limited(self.load_file(path)?.call(()))
+    }
+
+    /// Compile a Lua file to call any number of times; `Import` inside it
+    /// resolves relative to the file.
+    pub fn load_file<P: AsRef<Path>>(&self, path: P) -> Result<Function<'_>, Error> {
+        Ok(compile_lua_file(&self.lua, path.as_ref(), self.importer.cache.as_ref())?)
+    }
+
+    /// Run one of the scripts the game loads itself, with the mods that
+    /// import into it.
+    fn run_game_file(&self, scripts_dir: &Path, name: &str) -> Result<(), Error> {
+        let Importer { cache, mods, .. } = &self.importer;
+        for path in mods.top(name) {
+            exec_chunk(&self.lua, path, cache.as_ref())?;
+        }
+        self.run_file(scripts_dir.join(name))?;
+        for path in mods.bottom(name) {
+            exec_chunk(&self.lua, path, cache.as_ref())?;
+        }
+        Ok(())
+    }
+
+    pub fn exec(&self, chunk: &str) -> Result<(), Error> {
+        self.limited(self.lua.load(chunk).exec())
+    }
+
+    /// Evaluate a Lua expression or chunk and convert its results.
+    pub fn eval<'lua, T: FromLuaMulti<'lua>>(&'lua self, chunk: &str) -> Result<T, Error> {
+        self.limited(self.lua.load(chunk).eval())
+    }
+
+    /// Start the timeout and instruction count over, for example before
+    /// each query on a restored snapshot.
+    pub fn reset_limits(&self) {
+        if let Some(limits) = &self.limits {
+            limits.reset();
+        }
+    }
+
+    /// Report the allocator refusing memory over `max_memory` as the
+    /// limit it is; the hook reports the other limits itself.
+    fn limited<T>(&self, result: mlua::Result<T>) -> Result<T, Error> {
+        match (result, self.limits.as_ref().and_then(|limits| limits.limits().max_memory)) {
+            (Err(mlua::Error::MemoryError(_)), Some(max_memory)) => {
+                Err(Error::from(LimitExceeded { limit: Limit::Memory(max_memory), stack: Vec::new() }))
+            }
+            (result, _) => Ok(result?),
+        }
+    }
+
+    pub fn get<'lua, T: FromLua<'lua>>(&'lua self, name: &str) -> Result<T, Error> {
+        Ok(self.lua.globals().get(name)?)
+    }
+
+    pub fn set<'lua, T: IntoLua<'lua>>(&'lua self, name: &str, value: T) -> Result<(), Error> {
+        Ok(self.lua.globals().set(name, value)?)
+    }
+
+    /// Capture the current globals and RNG streams, e.g. once the game
+    /// scripts and save are loaded, to restore before every query.
+    pub fn snapshot(&self) -> Result<Snapshot<R>, Error> {
+        let globals = self.lua.globals();
+        let root = self.lua.create_table()?;
+        let mut copier = TableCopier::new(&self.lua, Some(Vec::new()))?;
+        copier.seen.insert(globals.to_pointer(), root.clone());
+        copier.copy_entries(&globals, &root)?;
+
+        let debug: Table = self.lua.registry_value(&self.debug)?;
+        let getupvalue: Function = debug.get("getupvalue")?;
+        let upvalueid: Function = debug.get("upvalueid")?;
+        let upvalues = self.lua.create_table()?;
+        let mut visited = HashSet::new();
+        let mut variables = HashSet::new();
+        // copying an upvalue can turn up more functions
+        while let Some(function) = copier.functions.as_mut().and_then(Vec::pop) {
+            if !visited.insert(function.to_pointer()) || function.info().what == "C" {
+                continue;
+            }
+            for index in 1.. {
+                let (name, value): (Option<mlua::String>, Value) = getupvalue.call((function.clone(), index))?;
+                if name.is_none() {
+                    break;
+                }
+                let id: LightUserData = upvalueid.call((function.clone(), index))?;
+                if variables.insert(id.0) {
+                    let value = copier.deep_copy(value)?;
+                    let upvalue = [Value::Function(function.clone()), Value::Integer(index), value];
+                    upvalues.raw_push(self.lua.create_sequence_from(upvalue)?)?;
+                }
+            }
+        }
+
+        Ok(Snapshot {
+            globals: self.lua.create_registry_value(root)?,
+            upvalues: self.lua.create_registry_value(upvalues)?,
+            streams: self.streams.borrow().clone(),
+            imported: self.importer.imported.borrow().clone(),
+        })
+    }
+
+    /// Put the globals and RNG streams back the way they were when
+    /// `snapshot` was taken. The snapshot is left untouched, so it can be
+    /// restored any number of times.
+    pub fn restore(&self, snapshot: &Snapshot<R>) -> Result<(), Error> {
+        let globals = self.lua.globals();
+        let root: Table = self.lua.registry_value(&snapshot.globals)?;
+
+        let mut stale = Vec::new();
+        for pair in globals.clone().pairs::<Value, Value>() {
+            let (key, _) = pair?;
+            if root.raw_get::<_, Value>(key.clone())?.is_nil() {
+                stale.push(key);
+            }
+        }
+        for key in stale {
+            globals.raw_set(key, Value::Nil)?;
+        }
+
+        let mut copier = TableCopier::new(&self.lua, None)?;
+        copier.seen.insert(root.to_pointer(), globals.clone());
+        copier.copy_entries(&root, &globals)?;
+
+        let debug: Table = self.lua.registry_value(&self.debug)?;
+        let setupvalue: Function = debug.get("setupvalue")?;
+        let upvalues: Table = self.lua.registry_value(&snapshot.upvalues)?;
+        for upvalue in upvalues.sequence_values::<Table>() {
+            let upvalue = upvalue?;
+            let (function, index, value): (Function, i64, Value) =
+                (upvalue.raw_get(1)?, upvalue.raw_get(2)?, upvalue.raw_get(3)?);
+            setupvalue.call::<_, ()>((function, index, copier.deep_copy(value)?))?;
+        }
+        *self.streams.borrow_mut() = snapshot.streams.clone();
+        *self.importer.imported.borrow_mut() = snapshot.imported.clone();
+        Ok(())
+    }
+
+    /// The engine calls recorded since the last call, if recording.
+    pub fn take_engine_calls(&self) -> Vec<EngineCall> {
+        self.engine.as_ref().map(|engine| engine.borrow_mut().take_calls()).unwrap_or_default()
+    }
+
+    /// Undefined globals the game scripts read, by name, if recording.
+    pub fn missing_engine_globals(&self) -> BTreeMap<String, MissingGlobal> {
+        self.engine.as_ref().map(|engine| engine.borrow().missing().clone()).unwrap_or_default()
+    }
+
+    /// The records emitted since the last call, unless they go to an
+    /// `on_emit` callback.
+    pub fn take_emitted(&self) -> Vec<EmitRecord> {
+        match &mut *self.emitted.borrow_mut() {
+            EmitSink::Collect(records) => std::mem::take(records),
+            EmitSink::Callback(_) => Vec::new(),
+        }
+    }
+
+    /// The time spent in each Lua call stack since the last call, if
+    /// profiling.
+    pub fn take_profile(&self) -> Option<Profile> {
+        self.profiler.as_ref().map(|profiler| profiler.take())
+    }
+
+    /// Flush the RNG trace, if any, and hand it back.
+    pub fn finish(&self) -> Result<Option<RngTrace>, Error> {
+        let mut trace = self.trace.borrow_mut().take();
+        if let Some(trace) = &mut trace {
+            trace.finish()?;
+        }
+        Ok(trace)
+    }
+
+    /// Run the profiler and limits from one hook, the only one a VM has.
+    fn install_instruction_hook(&self) {
+        if self.limits.is_none() && self.profiler.is_none() {
+            return;
+        }
+        let limits = self.limits.clone();
+        let profiler = self.profiler.clone();
+        self.lua.set_hook(HookTriggers::new().every_nth_instruction(limits::CHECK_INTERVAL), move |lua, _debug| {
+            if let Some(profiler) = &profiler {
+                profiler.sample(lua);
+            }
+            match &limits {
+                Some(limits) => limits.on_instructions(lua),
+                None => Ok(()),
+            }
+        });
+    }
+
+    fn install_output(&self, output: Box<dyn Write>) -> Result<(), Error> {
+        let output = RefCell::new(output);
+        let print = self.lua.create_function(move |lua, args: Variadic<Value>| {
+            let tostring: Function = lua.globals().get("tostring")?;
+            let mut line = Vec::new();
+            for (i, arg) in args.into_iter().enumerate() {
+                if i > 0 {
+                    line.push(b'\t');
+                }
+                let text: mlua::String = tostring.call(arg)?;
+                line.extend_from_slice(text.as_bytes());
+            }
+            line.push(b'\n');
+            output.borrow_mut().write_all(&line).map_err(mlua::Error::external)
+        })?;
+        self.lua.globals().set("print", print)?;
+        Ok(())
+    }
+
+    /// Watch reads of undefined globals: record the ones made by chunks
+    /// whose name starts with one of `game_sources` as missing engine
+    /// globals, and once `strict` is set, fail the others.
+    fn install_global_index(&self, game_sources: Vec<String>, strict: Rc<Cell<bool>>) -> Result<(), Error> {
+        let recorder = self.engine.clone();
+        let index = self.lua.create_function(move |lua, (_, name): (Value, Value)| {
+            let name = match &name {
+                Value::String(name) => name.to_string_lossy().into_owned(),
+                other => format!("{:?}", other),
+            };
+            // level 0 is this function, 1 the one reading the global
+            let source = lua.inspect_stack(1).and_then(|caller| caller.source().source.map(|source| source.into_owned()));
+            let from_game = match &source {
+                Some(source) => game_sources.iter().any(|game| source.starts_with(game.as_str())),
+                None => return Ok(Value::Nil),
+            };
+            if from_game {
+                if let Some(recorder) = &recorder {
+                    recorder.borrow_mut().record_missing(name, engine_calls::caller_location(lua));
+                }
+                Ok(Value::Nil)
+            } else if strict.get() {
+                let location = engine_calls::caller_location(lua).map(|location| location + ": ").unwrap_or_default();
+                Err(mlua::Error::runtime(format!("{}undefined global '{}'", location, name)))
+            } else {
+                Ok(Value::Nil)
+            }
+        })?;
+        let metatable = self.lua.create_table()?;
+        metatable.set("__index", index)?;
+        self.lua.globals().set_metatable(Some(metatable));
+        Ok(())
+    }
+
+    fn install_hooks(&self) -> Result<(), Error> {
+        let lua = &self.lua;
+
+        let getmetatable = lua.create_function(|_, table: Table| Ok(table.get_metatable()))?;
+        lua.globals().set("getmetatable", getmetatable)?;
+
+        // Engine callbacks etc.
+        let before_engine = global_functions(lua)?;
+        self.run_file("Engine.lua")?;
+        if let Some(recorder) = &self.engine {
+            for name in global_functions(lua)?.difference(&before_engine) {
+                engine_calls::record_calls(lua, recorder, name, None)?;
+            }
+            engine_calls::register(lua, recorder)?;
+        }
+
+        // Hooks into the engine for RNG, with the engine's signatures: randomseed(seed, id),
+        // randomint(min, max, id) and random(id). A missing id means the global stream.
+        let (streams, trace) = (self.streams.clone(), self.trace.clone());
+        let randomseed = lua.create_function(move |lua, (o_seed, id): (Option<i32>, Option<i32>)| {
+            let seed = o_seed.unwrap_or(0);
+            let stream = streams.borrow_mut().seed(seed as u64, id);
+            if let Some(trace) = trace.borrow_mut().as_mut() {
+                trace.seed(lua, stream, seed)?;
+            }
+            Ok(stream)
+        })?;
+        lua.globals().set("randomseed", randomseed)?;
+
+        let (streams, trace) = (self.streams.clone(), self.trace.clone());
+        let randomint = lua.create_function(move |lua, (min, max, id): (i32, i32, Value)| {
+            let mut streams = streams.borrow_mut();
+            let stream = streams.resolve(stream_id(&id));
+            match trace.borrow_mut().as_mut() {
+                Some(trace) => {
+                    let offset = streams.get(Some(stream)).uses();
+                    let mut rng = RecordingRng::new(streams.get(Some(stream)));
+                    let result = rand_int(&mut rng, min, max);
+                    trace.draw(lua, TraceRecord {
+                        call: "randomint",
+                        stream,
+                        offset,
+                        args: vec![min.into(), max.into(), lua_to_json(&id)],
+                        raw: rng.raw,
+                        result: result.into(),
+                        stack: Vec::new(),
+                    })?;
+                    Ok(result)
+                }
+                None => Ok(rand_int(streams.get(Some(stream)), min, max)),
+            }
+        })?;
+        lua.globals().set("randomint", randomint)?;
+
+        let (streams, trace) = (self.streams.clone(), self.trace.clone());
+        let random = lua.create_function(move |lua, args: Variadic<Value>| {
+            let mut streams = streams.borrow_mut();
+            // only the first argument is the id; anything after it isn't a stream
+            let stream = streams.resolve(args.first().and_then(stream_id));
+            match trace.borrow_mut().as_mut() {
+                Some(trace) => {
+                    let offset = streams.get(Some(stream)).uses();
+                    let mut rng = RecordingRng::new(streams.get(Some(stream)));
+                    let result = rand_double(&mut rng);
+                    trace.draw(lua, TraceRecord {
+                        call: "random",
+                        stream,
+                        offset,
+                        args: args.iter().map(lua_to_json).collect(),
+                        raw: rng.raw,
+                        result: result.into(),
+                        stack: Vec::new(),
+                    })?;
+                    Ok(result)
+                }
+                None => Ok(rand_double(streams.get(Some(stream)))),
+            }
+        })?;
+        lua.globals().set("random", random)?;
+
+        let streams = self.streams.clone();
+        let get_rng_uses = lua.create_function(move |_, id: Value| Ok(streams.borrow_mut().get(stream_id(&id)).uses()))?;
+        lua.globals().set("GetRngUses", get_rng_uses)?;
+
+        // Script-owned generators for look-ahead
+        lua_rng::register::<R>(lua)?;
+        let streams = self.streams.clone();
+        let from_stream =
+            lua.create_function(move |_, id: Value| Ok(LuaRng(streams.borrow_mut().get(stream_id(&id)).clone())))?;
+        lua.globals().get::<_, Table>("Rng")?.set("fromStream", from_stream)?;
+
+        let randomgaussian = lua.create_function(|_, _args: Variadic<Value>| {
+            Ok(0.0) // only affects enemy ratios in encounters, but not number of waves or types
+        })?;
+        lua.globals().set("randomgaussian", randomgaussian)?;
+
+        let sandbox = self.importer.sandbox.clone();
+        let luabins_read = lua.create_function(move |lua, filename: String| -> Result<Value, mlua::Error> {
+            if let Some(sandbox) = &sandbox {
+                sandbox.check(Path::new(&filename), Access::Read).map_err(mlua::Error::runtime)?;
+            }
+            let file_data = std::fs::read(Path::new(&filename))
+                .map_err(|e| mlua::Error::runtime(format!("Failed to read file '{}': {}", filename, e)))?;
+
+            let mut data_slice = file_data.as_slice();
+            match luabins::load(lua, &mut data_slice, format!("luabins_read {}", filename)) {
+                Ok(values) => {
+                    if values.len() == 1 {
+                        Ok(values.into_iter().next().unwrap())
+                    } else {
+                        Ok(Value::Table(lua.create_table_from(values.into_iter().enumerate())?))
+                    }
+                }
+                Err(e) => Err(mlua::Error::runtime(format!("Failed to parse luabins file '{}': {}", filename, e))),
+            }
+        })?;
+        lua.globals().set("LuabinsRead", luabins_read)?;
+
+        let sandbox = self.importer.sandbox.clone();
+        let luabins_write = lua.create_function(move |_, (filename, table): (String, Value)| -> Result<(), mlua::Error> {
+            if let Some(sandbox) = &sandbox {
+                sandbox.check(Path::new(&filename), Access::Write).map_err(mlua::Error::runtime)?;
+            }
+            let mut data = Vec::new();
+            match luabins::save(&mut data, vec![table]) {
+                Ok(()) => std::fs::write(Path::new(&filename), data)
+                    .map_err(|e| mlua::Error::runtime(format!("Failed to write file '{}': {}", filename, e))),
+                Err(e) => Err(mlua::Error::runtime(format!("Failed to serialize luabins data for '{}': {}", filename, e))),
+            }
+        })?;
+        lua.globals().set("LuabinsWrite", luabins_write)?;
+
+        // Structured output, kept apart from print
+        let emitted = self.emitted.clone();
+        let emit = lua.create_function(move |_, (name, data): (String, Value)| {
+            let data = emit::to_json(&data)
+                .map_err(|e| mlua::Error::runtime(format!("Emit('{}'): {}", name, e)))?;
+            match &mut *emitted.borrow_mut() {
+                EmitSink::Collect(records) => records.push(EmitRecord { name, data }),
+                EmitSink::Callback(on_emit) => on_emit(EmitRecord { name, data }),
+            }
+            Ok(())
+        })?;
+        lua.globals().set("Emit", emit)?;
+
+        Ok(())
+    }
+}
+
+const BYTE_ORDER_MARK: &[u8] = "\u{feff}".as_bytes();
+fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    let file = std::fs::read(path)?;
+    if file.starts_with(BYTE_ORDER_MARK) {
+        Ok(file[3..].to_vec())
+    } else {
+        Ok(file.to_vec())
+    }
+}
+
+fn compile_lua_file<'lua>(lua: &'lua Lua, path: &Path, cache: Option<&StartupCache>) -> Result<Function<'lua>, mlua::Error> {
+    let file = read_file(path)?;
+    let name = chunk_name(path);
+    match cache {
+        Some(cache) => cache.load_chunk(lua, path, &file, Some(&name)),
+        None => lua.load(&file).set_name(name).into_function(),
+    }
+}
+
+impl Importer {
+    /// Define the global `Import(name, { Force = true })`, resolving `name`
+    /// relative to the file the calling code is in.
+    fn install(&self, lua: &Lua) -> mlua::Result<()> {
+        let importer = self.clone();
+        let import = lua.create_function(move |lua, (name, options): (String, Option<Table>)| {
+            let force = match options {
+                Some(options) => options.get::<_, Option<bool>>("Force")?.unwrap_or(false),
+                None => false,
+            };
+            importer.import(lua, &calling_dir(lua)?, &name, force)
+        })?;
+        lua.globals().set("Import", import)
+    }
+
+    /// `Import(name)` from a file in `dir`: run the first match for `name`
+    /// on the search path, with the mods imported into it, unless it has
+    /// already been imported and `force` isn't set.
+    fn import(&self, lua: &Lua, dir: &Path, name: &str, force: bool) -> mlua::Result<()> {
+        let path = self.resolve(dir, name)?;
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.check(&path, Access::Read).map_err(mlua::Error::runtime)?;
+        }
+        let key = path.canonicalize()?;
+        if !self.imported.borrow_mut().insert(key.clone()) && !force {
+            return Ok(());
+        }
+
+        let result = (|| {
+            for mod_path in self.mods.top(name) {
+                exec_chunk(lua, mod_path, self.cache.as_ref())?;
+            }
+            exec_chunk(lua, &path, self.cache.as_ref())?;
+            for mod_path in self.mods.bottom(name) {
+                exec_chunk(lua, mod_path, self.cache.as_ref())?;
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            // let a fixed file be imported again
+            self.imported.borrow_mut().remove(&key);
+        }
+        result
+    }
+
+    fn resolve(&self, dir: &Path, name: &str) -> mlua::Result<PathBuf> {
+        let candidates: Vec<PathBuf> = std::iter::once(dir)
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .collect();
+        match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => Ok(path.clone()),
+            None => {
+                let searched: Vec<String> = candidates.iter().map(|path| path.display().to_string()).collect();
+                Err(mlua::Error::runtime(format!("Import: '{}' not found, searched: {}", name, searched.join(", "))))
+            }
+        }
+    }
+}
+
+/// Directory of the file holding the innermost Lua function calling the
+/// current Rust function, or the working directory for code that isn't
+/// from a file, like `exec`'d chunks.
+fn calling_dir(lua: &Lua) -> mlua::Result<PathBuf> {
+    // level 0 is the Rust function itself
+    let file = lua_frames(lua, 1).find_map(|debug| {
+        let source = debug.source().source?.into_owned();
+        source.strip_prefix('@').map(PathBuf::from)
+    });
+    let dir = file.as_deref().and_then(Path::parent).filter(|dir| !dir.as_os_str().is_empty());
+    Ok(std::env::current_dir()?.join(dir.unwrap_or_else(|| Path::new("."))))
+}
+
+/// Run the Lua file at `path` as an imported chunk.
+fn exec_chunk(lua: &Lua, path: &Path, cache: Option<&StartupCache>) -> mlua::Result<()> {
+    let file = read_file(path)?;
+    let name = chunk_name(path);
+    match cache {
+        Some(cache) => cache.load_chunk(lua, path, &file, Some(&name))?.call(()),
+        None => lua.load(&file).set_name(name).exec(),
+    }
+}
+
+/// Chunk name for the file at `path`, so that errors and tracebacks show
+/// it as `path:line`, relative to the working directory when inside it.
+fn chunk_name(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let shown = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf))
+        .unwrap_or(path);
+    format!("@{}", shown.display())
+}
+
+/// Names of the global functions.
+fn global_functions(lua: &Lua) -> mlua::Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for pair in lua.globals().pairs::<Value, Value>() {
+        if let (Value::String(name), Value::Function(_)) = pair? {
+            names.insert(name.to_str()?.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Copies tables for snapshots. `seen` maps tables already copied to
+/// their copy, which keeps shared and cyclic references intact; it starts
+/// out with the tables snapshots share rather than copy, the standard
+/// libraries and everything else in `package.loaded`.
+struct TableCopier<'lua> {
+    lua: &'lua Lua,
+    seen: HashMap<*const c_void, Table<'lua>>,
+    /// The functions come across, if collecting them for their upvalues.
+    functions: Option<Vec<Function<'lua>>>,
+}
+
+impl<'lua> TableCopier<'lua> {
+    fn new(lua: &'lua Lua, functions: Option<Vec<Function<'lua>>>) -> mlua::Result<Self> {
+        let mut seen = HashMap::new();
+        let package: Table = lua.globals().get("package")?;
+        let loaded: Table = package.get("loaded")?;
+        for pair in loaded.clone().pairs::<Value, Value>() {
+            if let (_, Value::Table(table)) = pair? {
+                seen.insert(table.to_pointer(), table);
+            }
+        }
+        seen.insert(loaded.to_pointer(), loaded);
+        seen.insert(package.to_pointer(), package);
+        Ok(TableCopier { lua, seen, functions })
+    }
+
+    /// Copy every entry of `from` into `to`, copying nested tables.
+    fn copy_entries(&mut self, from: &Table<'lua>, to: &Table<'lua>) -> mlua::Result<()> {
+        for pair in from.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let key = self.deep_copy(key)?;
+            let value = self.deep_copy(value)?;
+            to.raw_set(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn deep_copy(&mut self, value: Value<'lua>) -> mlua::Result<Value<'lua>> {
+        let table = match value {
+            Value::Table(table) => table,
+            Value::Function(function) => {
+                if let Some(functions) = &mut self.functions {
+                    functions.push(function.clone());
+                }
+                return Ok(Value::Function(function));
+            }
+            other => return Ok(other),
+        };
+        if let Some(copy) = self.seen.get(&table.to_pointer()) {
+            return Ok(Value::Table(copy.clone()));
+        }
+        let copy = self.lua.create_table()?;
+        self.seen.insert(table.to_pointer(), copy.clone());
+        self.copy_entries(&table, &copy)?;
+        copy.set_metatable(table.get_metatable());
+        Ok(Value::Table(copy))
+    }
+}
+
+fn stream_id(value: &Value) -> Option<i32> {
+    match value {
+        Value::Integer(i) => Some(*i as i32),
+        Value::Number(n) => Some(*n as i32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_simulator_hooks_and_output() {
+        let output = CapturedOutput::default();
+        let sim = Simulator::builder()
+            .lua_var("Seed=42".parse().unwrap())
+            .lua_var("Name=zag".parse().unwrap())
+            .output(output.clone())
+            .build()
+            .unwrap();
+
+        sim.exec("randomseed(Seed) print(Name, randomint(1, 6)) Emit('Hero', { Name = Name })").unwrap();
+        let uses: i64 = sim.eval("GetRngUses()").unwrap();
+        assert_eq!(uses, 1);
+        // the id is random's first argument, not whatever comes last
+        let stream_uses: (i64, i64) = sim.eval("randomseed(1, 3) random(3, 9) return GetRngUses(3), GetRngUses(9)").unwrap();
+        assert_eq!(stream_uses, (1, 0));
+
+        let mut expected = SggPcg::new(42);
+        let roll = rand_int(&mut expected, 1, 6);
+        assert_eq!(output.take(), format!("zag\t{}\n", roll));
+        assert_eq!(sim.get::<String>("Name").unwrap(), "zag");
+        assert_eq!(
+            sim.take_emitted(),
+            vec![EmitRecord { name: "Hero".to_string(), data: serde_json::json!({ "Name": "zag" }) }]
+        );
+    }
+
+    #[test]
+    fn test_import_searches_lib_dirs_once() {
+        let dir = TempDir::new("import");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib").join("Helper.lua"), "HelperRuns = (HelperRuns or 0) + 1").unwrap();
+        std::fs::write(
+            dir.join("route.lua"),
+            "Import 'Helper.lua' Import 'Helper.lua' Import('Helper.lua', { Force = true })",
+        )
+        .unwrap();
+        std::fs::write(dir.join("missing.lua"), "Import 'Nowhere.lua'").unwrap();
+
+        let sim = Simulator::builder().lib_dir(dir.join("lib")).build().unwrap();
+        let route = sim.run_file(dir.join("route.lua"));
+        let missing = sim.run_file(dir.join("missing.lua"));
+
+        route.unwrap();
+        assert_eq!(sim.get::<i64>("HelperRuns").unwrap(), 2);
+        let err = missing.unwrap_err().to_string();
+        assert!(err.contains("'Nowhere.lua' not found, searched:"), "{}", err);
+    }
+
+    #[test]
+    fn test_import_resolves_from_importing_file() {
+        let dir = TempDir::new("import-relative");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("A.lua"), "Import 'sub/B.lua' Import 'C.lua'").unwrap();
+        std::fs::write(dir.join("sub").join("B.lua"), "Import 'D.lua'").unwrap();
+        std::fs::write(dir.join("sub").join("C.lua"), "CFrom = 'sub'").unwrap();
+        std::fs::write(dir.join("sub").join("D.lua"), "DRan = true").unwrap();
+        std::fs::write(dir.join("C.lua"), "CFrom = 'A'").unwrap();
+        std::fs::write(dir.join("sub").join("Other.lua"), "").unwrap();
+
+        let sim = Simulator::builder().build().unwrap();
+        // compiling another file in between mustn't change where A imports from
+        let a = sim.load_file(dir.join("A.lua")).unwrap();
+        sim.load_file(dir.join("sub").join("Other.lua")).unwrap();
+        a.call::<_, ()>(()).unwrap();
+
+        assert!(sim.get::<bool>("DRan").unwrap());
+        assert_eq!(sim.get::<String>("CFrom").unwrap(), "A");
+    }
+
+    #[test]
+    fn test_strict_spares_game_scripts() {
+        let scripts_dir = TempDir::new("strict");
+        std::fs::write(scripts_dir.join("Main.lua"), "function IsModded() return ModUtil ~= nil end").unwrap();
+        std::fs::write(scripts_dir.join("RoomManager.lua"), "").unwrap();
+        let sim = Simulator::builder().scripts_dir(scripts_dir.path()).strict().build().unwrap();
+
+        assert!(!sim.eval::<bool>("IsModded()").unwrap());
+        assert!(sim.eval::<Value>("rawget(_G, 'ModUtil')").is_ok());
+        let err = sim.eval::<Value>("ModUtil").unwrap_err().to_string();
+        assert!(err.contains("undefined global 'ModUtil'"), "{}", err);
+    }
+
+    #[test]
+    fn test_restore_snapshot() {
+        let sim = Simulator::builder().build().unwrap();
+        sim.exec(
+            r#"
+            Run = { Rooms = { "A_Combat01" }, Depth = 1 }
+            Run.Self = Run
+            randomseed(42)
+            randomint(1, 6)
+            "#,
+        )
+        .unwrap();
+        let snapshot = sim.snapshot().unwrap();
+
+        for _ in 0..2 {
+            sim.exec(
+                r#"
+                table.insert(Run.Rooms, "A_Combat02")
+                Run.Depth = Run.Depth + 1
+                Extra = true
+                randomint(1, 6)
+                "#,
+            )
+            .unwrap();
+            sim.restore(&snapshot).unwrap();
+
+            let (rooms, depth, extra, cyclic): (i64, i64, bool, bool) =
+                sim.eval("return #Run.Rooms, Run.Depth, Extra ~= nil, Run.Self == Run").unwrap();
+            assert_eq!((rooms, depth, extra, cyclic), (1, 1, false, true));
+            assert_eq!(sim.eval::<i64>("GetRngUses()").unwrap(), 1);
+            assert!(sim.eval::<bool>("return _G._G == _G and string == package.loaded.string").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_restore_file_locals() {
+        let scripts_dir = TempDir::new("snapshot-locals");
+        std::fs::write(
+            scripts_dir.join("Main.lua"),
+            r#"
+            local Counts = {}
+            local Total = 0
+            SharedCounts = Counts
+            function Visit(name)
+                Counts[name] = (Counts[name] or 0) + 1
+                Total = Total + 1
+                return Counts[name], Total, SharedCounts[name]
+            end
+            "#,
+        )
+        .unwrap();
+        std::fs::write(scripts_dir.join("RoomManager.lua"), "").unwrap();
+        let build = || Simulator::builder().scripts_dir(scripts_dir.path()).build().unwrap();
+        let fresh: (i64, i64, i64) = build().eval("Visit('A')").unwrap();
+
+        let sim = build();
+        let snapshot = sim.snapshot().unwrap();
+        for _ in 0..2 {
+            sim.exec("Visit('A') Visit('B')").unwrap();
+            sim.restore(&snapshot).unwrap();
+            assert_eq!(sim.eval::<(i64, i64, i64)>("Visit('A')").unwrap(), fresh);
+            sim.restore(&snapshot).unwrap();
+        }
+    }
+}